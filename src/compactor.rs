@@ -0,0 +1,465 @@
+use std::fs::{self, File};
+use std::io::Result as IOResult;
+use std::io::{Error, ErrorKind};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use fileset::Fileset;
+use merger::Merger;
+use reader::{Read, Reader};
+use tombstone;
+use writer::{Write, WriterOptions};
+
+/// The fewest files merged at once by default.
+const DEFAULT_MIN_FILES: usize = 4;
+
+/// The most files merged at once by default.
+const DEFAULT_MAX_FILES: usize = 32;
+
+/// How far apart in size files merged together can be by default.
+const DEFAULT_SIZE_RATIO: f64 = 2.0;
+
+/// Options for a `Compactor`.
+///
+/// # Examples
+///
+/// ```
+/// let compactor = CompactorOptions::new().min_files(8).size_ratio(1.5).create(fileset);
+/// ```
+#[derive(Clone,Copy)]
+pub struct CompactorOptions {
+    /// The fewest similarly sized files to merge into one. Default is 4.
+    pub min_files: Option<usize>,
+    /// The most files to merge into one at a time. Default is 32.
+    pub max_files: Option<usize>,
+    /// How different in size files can be and still be merged together: a file is similar to a
+    /// run of files if it is no more than this many times larger or smaller than their average
+    /// size. Default is 2.
+    pub size_ratio: Option<f64>,
+    /// The options used to write merged files. Default is the `Writer` defaults.
+    pub writer_options: Option<WriterOptions>,
+    /// Whether the files are [tombstone](tombstone/index.html)-encoded, so that merging drops
+    /// the entries that deletes hide, and the deletes themselves once nothing older is left for
+    /// them to hide. Default is false.
+    pub tombstones: Option<bool>,
+}
+
+impl CompactorOptions {
+    /// Create a `CompactorOptions` with only defaults.
+    pub fn new() -> CompactorOptions {
+        CompactorOptions {
+            min_files: None,
+            max_files: None,
+            size_ratio: None,
+            writer_options: None,
+            tombstones: None,
+        }
+    }
+
+    /// Create a modified `CompactorOptions` with min_files set.
+    pub fn min_files(self: &Self, min_files: usize) -> CompactorOptions {
+        CompactorOptions { min_files: Some(min_files), ..*self }
+    }
+
+    /// Create a modified `CompactorOptions` with max_files set.
+    pub fn max_files(self: &Self, max_files: usize) -> CompactorOptions {
+        CompactorOptions { max_files: Some(max_files), ..*self }
+    }
+
+    /// Create a modified `CompactorOptions` with size_ratio set.
+    pub fn size_ratio(self: &Self, size_ratio: f64) -> CompactorOptions {
+        CompactorOptions { size_ratio: Some(size_ratio), ..*self }
+    }
+
+    /// Create a modified `CompactorOptions` with writer_options set.
+    pub fn writer_options(self: &Self, writer_options: WriterOptions) -> CompactorOptions {
+        CompactorOptions { writer_options: Some(writer_options), ..*self }
+    }
+
+    /// Create a modified `CompactorOptions` with tombstones set.
+    pub fn tombstones(self: &Self, tombstones: bool) -> CompactorOptions {
+        CompactorOptions { tombstones: Some(tombstones), ..*self }
+    }
+
+    /// Create a `Compactor` with these options for a `Fileset`.
+    pub fn create(self: &Self, fileset: Arc<Fileset>) -> Compactor {
+        Compactor {
+            options: *self,
+            fileset,
+            compacting: Mutex::new(()),
+            obsolete: Mutex::new(Vec::new()),
+        }
+    }
+}
+
+/// Merges groups of similarly sized files in a `Fileset` into one, so that the
+/// set stays small as new files are added.
+///
+/// Compaction is size-tiered: it looks for runs of files that are next to
+/// each other in merge order and similar in size, merges the run with the
+/// smallest files into a new file in the setfile's directory, and swaps it
+/// into the set in place of the run. Merging keeps the files' merge order, so
+/// reads see the same values as before, provided that the fileset's `MergeFn`
+/// is associative (as choosing the first or last value is).
+///
+/// Merged files are deleted once nothing in this process is reading them any
+/// more, including `Snapshot`s and iterators opened before the swap.
+///
+/// For tombstone-encoded files (see
+/// [`CompactorOptions::tombstones`](struct.CompactorOptions.html#structfield.tombstones)),
+/// merging drops deleted entries, and drops the deletes too when the run
+/// starts with the oldest file.
+///
+/// Only filesets with a setfile can be compacted.
+///
+/// # Examples
+///
+/// ```
+/// let fileset = Arc::new(Fileset::open_from_path("/srv/data.mtbl-fileset", merge_fn)?);
+/// let compactor = Compactor::new(fileset.clone());
+/// let background = compactor.spawn(Duration::from_secs(60));
+/// ```
+pub struct Compactor {
+    /// The options used to create this `Compactor`.
+    pub options: CompactorOptions,
+    fileset: Arc<Fileset>,
+    // Held while compacting, so that compactions from several threads don't
+    // merge the same run.
+    compacting: Mutex<()>,
+    // Files that have been compacted away, to delete once they are unused.
+    obsolete: Mutex<Vec<(PathBuf, Weak<Reader>)>>,
+}
+
+impl Compactor {
+    /// Create a `Compactor` with the default options for a `Fileset`.
+    pub fn new(fileset: Arc<Fileset>) -> Compactor {
+        CompactorOptions::new().create(fileset)
+    }
+
+    /// The `Fileset` being compacted.
+    pub fn fileset(&self) -> &Arc<Fileset> {
+        &self.fileset
+    }
+
+    /// The run of files that the next compaction would merge, in merge order,
+    /// if there is one.
+    pub fn plan(&self) -> Option<Vec<PathBuf>> {
+        let files = self.fileset.snapshot().loaded_files();
+        let sizes: Vec<u64> = files.iter().map(|&(_, size, _)| size).collect();
+        plan_run(&sizes,
+                 self.options.min_files.unwrap_or(DEFAULT_MIN_FILES),
+                 self.options.max_files.unwrap_or(DEFAULT_MAX_FILES),
+                 self.options.size_ratio.unwrap_or(DEFAULT_SIZE_RATIO))
+            .map(|run| files[run].iter().map(|f| f.0.clone()).collect())
+    }
+
+    /// Merge one run of similarly sized files, if there is one, returning the
+    /// path of the new file.
+    ///
+    /// If the fileset changes while the files are being merged so that the run
+    /// is gone, the new file is discarded and nothing changes.
+    pub fn compact_once(&self) -> IOResult<Option<PathBuf>> {
+        let _compacting = self.compacting.lock().unwrap();
+        self.delete_obsolete()?;
+        let files = self.fileset.snapshot().loaded_files();
+        let sizes: Vec<u64> = files.iter().map(|&(_, size, _)| size).collect();
+        let run = match plan_run(&sizes,
+                                 self.options.min_files.unwrap_or(DEFAULT_MIN_FILES),
+                                 self.options.max_files.unwrap_or(DEFAULT_MAX_FILES),
+                                 self.options.size_ratio.unwrap_or(DEFAULT_SIZE_RATIO)) {
+            Some(run) => run,
+            None => return Ok(None),
+        };
+        // Nothing is older than the first file for deletes to hide.
+        let bottom = run.start == 0;
+        let inputs = &files[run];
+        let output = self.output_path();
+        self.merge(inputs.iter().map(|f| f.2.clone()).collect(), &output, bottom)?;
+        let paths: Vec<&Path> = inputs.iter().map(|f| f.0.as_path()).collect();
+        match self.fileset.swap_files(&paths, &output) {
+            Ok(true) => {}
+            Ok(false) => {
+                fs::remove_file(&output)?;
+                return Ok(None);
+            }
+            Err(e) => {
+                let _ = fs::remove_file(&output);
+                return Err(e);
+            }
+        }
+        self.obsolete
+            .lock()
+            .unwrap()
+            .extend(inputs.iter().map(|f| (f.0.clone(), Arc::downgrade(&f.2))));
+        drop(files);
+        self.delete_obsolete()?;
+        Ok(Some(output))
+    }
+
+    /// Compact until there is nothing left to merge, returning the number of
+    /// files written.
+    pub fn compact(&self) -> IOResult<usize> {
+        let mut count = 0;
+        while self.compact_once()?.is_some() {
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Delete the files that have been compacted away and are no longer being
+    /// read, returning how many were deleted. Files still being read are
+    /// deleted by a later call.
+    pub fn delete_obsolete(&self) -> IOResult<usize> {
+        let mut obsolete = self.obsolete.lock().unwrap();
+        let mut deleted = 0;
+        let mut result = Ok(());
+        obsolete.retain(|(path, reader)| {
+            if reader.upgrade().is_some() || result.is_err() {
+                return true;
+            }
+            match fs::remove_file(path) {
+                Err(ref e) if e.kind() != ErrorKind::NotFound => {
+                    result = Err(Error::new(e.kind(),
+                                            format!("failed to delete compacted MTBL file {}: \
+                                                     {}",
+                                                    path.display(),
+                                                    e)));
+                    true
+                }
+                _ => {
+                    deleted += 1;
+                    false
+                }
+            }
+        });
+        result.map(|_| deleted)
+    }
+
+    /// Compact in a background thread, checking for work every `interval`
+    /// until the returned `CompactorThread` is stopped or dropped.
+    pub fn spawn(self, interval: Duration) -> CompactorThread {
+        Arc::new(self).spawn_shared(interval)
+    }
+
+    /// Like [`spawn`](#method.spawn), but keeping the `Compactor` usable from
+    /// other threads too, for example to compact on demand or delete obsolete
+    /// files. Only one compaction runs at a time.
+    pub fn spawn_shared(self: &Arc<Self>, interval: Duration) -> CompactorThread {
+        let compactor = self.clone();
+        let stop = Arc::new((Mutex::new(false), Condvar::new()));
+        let thread_stop = stop.clone();
+        let thread = thread::spawn(move || {
+            let (stopped, wakeup) = &*thread_stop;
+            let mut stopped = stopped.lock().unwrap();
+            while !*stopped {
+                compactor.compact()?;
+                stopped = wakeup.wait_timeout(stopped, interval).unwrap().0;
+            }
+            Ok(())
+        });
+        CompactorThread {
+            stop,
+            thread: Some(thread),
+        }
+    }
+
+    /// A fresh path in the setfile's directory for a merged file.
+    fn output_path(&self) -> PathBuf {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        self.fileset.dir().join(format!("compacted-{}{:09}-{}.mtbl",
+                                        now.as_secs(),
+                                        now.subsec_nanos(),
+                                        process::id()))
+    }
+
+    /// Merge `readers` into a new file at `output`, which will be the `bottom`
+    /// (oldest) file if they include the oldest. The file is written under a
+    /// temporary name and only renamed into place when complete.
+    fn merge(&self, readers: Vec<Arc<Reader>>, output: &Path, bottom: bool) -> IOResult<()> {
+        let temp = output.with_file_name(format!(".{}.tmp",
+                                                 output.file_name().unwrap().to_string_lossy()));
+        let written = (|| {
+            let merge_fn = self.fileset.merge_fn();
+            let merger = Merger::new(readers, move |key, val0, val1| merge_fn(key, val0, val1));
+            let mut writer = self.options
+                                 .writer_options
+                                 .unwrap_or_else(WriterOptions::new)
+                                 .create_from_path(&temp)?;
+            let out_of_order = |_| {
+                Error::new(ErrorKind::InvalidData, "MTBL files to compact are out of order")
+            };
+            if self.options.tombstones.unwrap_or(false) {
+                tombstone::compact(&merger, &mut writer, bottom).map_err(out_of_order)?;
+            } else {
+                for (key, value) in merger.iter() {
+                    writer.add(key, value).map_err(out_of_order)?;
+                }
+            }
+            // Dropping the writer finishes the file.
+            drop(writer);
+            File::open(&temp)?.sync_all()?;
+            fs::rename(&temp, output)
+        })();
+        if written.is_err() {
+            let _ = fs::remove_file(&temp);
+        }
+        written
+    }
+}
+
+/// A `Compactor` running in a background thread, from
+/// [`Compactor::spawn`](struct.Compactor.html#method.spawn). Dropping it stops
+/// the thread.
+pub struct CompactorThread {
+    stop: Arc<(Mutex<bool>, Condvar)>,
+    thread: Option<JoinHandle<IOResult<()>>>,
+}
+
+impl CompactorThread {
+    /// Stop compacting, waiting for a compaction in progress to finish.
+    /// Returns the error that stopped the thread early, if any.
+    pub fn stop(mut self) -> IOResult<()> {
+        self.join()
+    }
+
+    fn join(&mut self) -> IOResult<()> {
+        *self.stop.0.lock().unwrap() = true;
+        self.stop.1.notify_all();
+        match self.thread.take().map(|t| t.join()) {
+            Some(Ok(result)) => result,
+            Some(Err(_)) => Err(Error::new(ErrorKind::Interrupted, "MTBL compactor thread panicked")),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for CompactorThread {
+    fn drop(&mut self) {
+        let _ = self.join();
+    }
+}
+
+/// Choose the run of files to merge: of the runs of at least `min_files`
+/// neighbouring files of similar size, the one with the smallest files, cut
+/// to at most `max_files`.
+fn plan_run(sizes: &[u64], min_files: usize, max_files: usize, ratio: f64) -> Option<Range<usize>> {
+    let min_files = min_files.max(2);
+    let max_files = max_files.max(min_files);
+    let mut best: Option<(f64, Range<usize>)> = None;
+    let mut start = 0;
+    while start < sizes.len() {
+        let mut total = sizes[start] as f64;
+        let mut end = start + 1;
+        while end < sizes.len() && end - start < max_files {
+            let average = (total / (end - start) as f64).max(1.0);
+            let size = (sizes[end] as f64).max(1.0);
+            if size > average * ratio || size * ratio < average {
+                break;
+            }
+            total += sizes[end] as f64;
+            end += 1;
+        }
+        let average = total / (end - start) as f64;
+        if end - start >= min_files && best.as_ref().is_none_or(|b| average < b.0) {
+            best = Some((average, start..end));
+        }
+        start = end;
+    }
+    best.map(|(_, run)| run)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use compactor::{plan_run, Compactor, CompactorOptions};
+    use fileset::Fileset;
+    use merger::Merger;
+    use reader::Read;
+    use test_util::{create_mtbl, write_setfile, TempDir};
+    use writer::WriterOptions;
+
+    /// A fileset of one big file followed by five small ones, each of which
+    /// overrides key "k" and adds a key of its own.
+    fn set_up_fileset(dir: &TempDir) -> Arc<Fileset> {
+        let big: Vec<_> = (0..2000).map(|i| (format!("big{:05}", i), format!("{:x}", i * 7919)))
+                                   .collect();
+        create_mtbl(dir.path().join("big.mtbl"), WriterOptions::new(), &big);
+        let mut names = vec!["big.mtbl".to_string()];
+        for i in 0..5 {
+            let name = format!("small{}.mtbl", i);
+            create_mtbl(dir.path().join(&name),
+                        WriterOptions::new(),
+                        &[("k".to_string(), format!("v{}", i)),
+                          (format!("small{}", i), "x".to_string())]);
+            names.push(name);
+        }
+        let setfile = dir.path().join("fs.mtbl-fileset");
+        write_setfile(&setfile, &names);
+        Arc::new(Fileset::open_from_path(&setfile, Box::new(Merger::merge_choose_last_value))
+                     .unwrap())
+    }
+
+    #[test]
+    fn test_plan_run() {
+        assert_eq!(plan_run(&[100, 10, 11, 9, 12, 1000], 4, 32, 2.0), Some(1..5));
+        assert_eq!(plan_run(&[100, 10, 11, 9, 1000], 4, 32, 2.0), None);
+        // The run of smaller files goes first, and runs are cut to max_files.
+        assert_eq!(plan_run(&[50, 50, 50, 5, 5, 5, 5], 3, 3, 2.0), Some(3..6));
+        assert_eq!(plan_run(&[0, 0, 0], 2, 32, 2.0), Some(0..3));
+    }
+
+    #[test]
+    fn test_compactor() {
+        let dir = TempDir::new();
+        let fileset = set_up_fileset(&dir);
+        let before: Vec<_> = fileset.iter().collect();
+        let compactor = CompactorOptions::new().min_files(3).create(fileset.clone());
+        let plan = compactor.plan().unwrap();
+        assert_eq!(plan.len(), 5);
+
+        let snapshot = fileset.snapshot();
+        let output = compactor.compact_once().unwrap().unwrap();
+        assert_eq!(fileset.files().len(), 2);
+        assert_eq!(fileset.files()[1].path, output);
+        assert_eq!(fileset.iter().collect::<Vec<_>>(), before);
+        assert_eq!(fileset.get("k"), Some("v4".as_bytes().to_vec()));
+
+        // The merged files are kept while a snapshot is reading them.
+        assert!(plan.iter().all(|p| p.exists()));
+        assert_eq!(snapshot.get("small0"), Some("x".as_bytes().to_vec()));
+        drop(snapshot);
+        assert_eq!(compactor.delete_obsolete().unwrap(), 5);
+        assert!(plan.iter().all(|p| !p.exists()));
+        assert_eq!(compactor.compact().unwrap(), 0);
+
+        let reopened = Fileset::open_from_path(dir.path().join("fs.mtbl-fileset"),
+                                               Box::new(Merger::merge_choose_last_value))
+                           .unwrap();
+        assert_eq!(reopened.iter().collect::<Vec<_>>(), before);
+    }
+
+    #[test]
+    fn test_spawn() {
+        let dir = TempDir::new();
+        let fileset = set_up_fileset(&dir);
+        let background = CompactorOptions::new()
+                             .min_files(5)
+                             .create(fileset.clone())
+                             .spawn(Duration::from_millis(10));
+        let start = Instant::now();
+        while fileset.files().len() > 2 && start.elapsed() < Duration::from_secs(10) {
+            thread::sleep(Duration::from_millis(10));
+        }
+        background.stop().unwrap();
+        assert_eq!(fileset.files().len(), 2);
+        assert_eq!(fileset.get("k"), Some("v4".as_bytes().to_vec()));
+        let _ = Compactor::new(fileset);
+    }
+}
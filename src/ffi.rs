@@ -0,0 +1,41 @@
+//! Declarations for the public APIs of the compression libraries that libmtbl
+//! is built with, for decompressing data blocks outside of libmtbl.
+
+use libc::{c_char, c_int, c_ulong, size_t};
+
+pub const Z_OK: c_int = 0;
+pub const Z_BUF_ERROR: c_int = -5;
+
+pub const SNAPPY_OK: c_int = 0;
+
+#[link(name="z")]
+extern "C" {
+    pub fn uncompress(dest: *mut u8,
+                      dest_len: *mut c_ulong,
+                      source: *const u8,
+                      source_len: c_ulong)
+                      -> c_int;
+}
+
+#[link(name="snappy")]
+extern "C" {
+    pub fn snappy_uncompressed_length(compressed: *const c_char,
+                                      compressed_length: size_t,
+                                      result: *mut size_t)
+                                      -> c_int;
+
+    pub fn snappy_uncompress(compressed: *const c_char,
+                             compressed_length: size_t,
+                             uncompressed: *mut c_char,
+                             uncompressed_length: *mut size_t)
+                             -> c_int;
+}
+
+#[link(name="lz4")]
+extern "C" {
+    pub fn LZ4_decompress_safe(source: *const c_char,
+                               dest: *mut c_char,
+                               compressed_size: c_int,
+                               max_decompressed_size: c_int)
+                               -> c_int;
+}
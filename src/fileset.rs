@@ -1,10 +1,34 @@
-use libc::c_void;
-use std::ffi::CString;
-use std::path::Path;
+use std::borrow::Cow;
+use std::collections::{HashSet, VecDeque};
+use std::ffi::{OsStr, OsString};
+use std::fs::{self, File};
+use std::io::Read as IORead;
+use std::io::Result as IOResult;
+use std::io::Write as IOWrite;
+use std::io::{Error, ErrorKind};
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::thread;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime};
 
-use merger;
+use merger::{Merger, SyncMergeFn};
 use mtbl_sys;
-use reader::{Read, Iter};
+use reader::{CompressionType, Iter, KeyBounds, Nearest, Query, Read, Reader};
+use source::NO_MTBL_SOURCE;
+use tombstone::{self, Tombstoned};
+use watcher::Watcher;
+
+/// The mtbl default reload interval.
+const DEFAULT_RELOAD_INTERVAL_SECONDS: u32 = 60;
+
+/// How often a watching `Fileset`'s background thread retries a reload that
+/// was put off, and checks whether the `Fileset` has been dropped.
+const WATCH_POLL_INTERVAL: Duration = Duration::from_millis(200);
 
 /// Options for opening an MTBL fileset.
 ///
@@ -18,12 +42,39 @@ pub struct FilesetOptions {
     /// How often, in seconds, to reload the fileset description file to look for new file entries.
     /// The mtbl default is 60 seconds.
     pub reload_interval_seconds: Option<u32>,
+    /// Whether reloads must succeed completely. In strict mode, a reload that finds a listed file
+    /// that cannot be opened keeps serving the previous set of files; otherwise the bad file is
+    /// skipped and the rest are served. The default is false.
+    pub strict: Option<bool>,
+    /// Whether to watch the setfile and the MTBL files with filesystem notifications (inotify),
+    /// instead of polling. When watching, a background thread reloads shortly after a change
+    /// happens, and runs the [`on_change`](struct.Fileset.html#method.on_change) callbacks, without
+    /// waiting for a read; the reload interval is ignored. This is only supported on Linux. The
+    /// default is false.
+    pub watch: Option<bool>,
+    /// For a `Fileset` opened from a directory, how to order the files, from the first merged to
+    /// the last. The default is by name.
+    pub order: Option<FileOrder>,
+}
+
+/// The merge order of the files in a `Fileset` opened from a directory.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum FileOrder {
+    /// By file name, compared byte by byte.
+    Name,
+    /// By modification time, oldest first, then by name.
+    Modified,
 }
 
 impl FilesetOptions {
     /// Create a `FilesetOptions` with only defaults.
     pub fn new() -> FilesetOptions {
-        FilesetOptions { reload_interval_seconds: None }
+        FilesetOptions {
+            reload_interval_seconds: None,
+            strict: None,
+            watch: None,
+            order: None,
+        }
     }
 
     /// Create a modified `FilesetOptions` with reload_interval_seconds set.
@@ -31,32 +82,89 @@ impl FilesetOptions {
         FilesetOptions { reload_interval_seconds: Some(reload_interval_seconds), ..*self }
     }
 
+    /// Create a modified `FilesetOptions` with strict set.
+    pub fn strict(self: &Self, strict: bool) -> FilesetOptions {
+        FilesetOptions { strict: Some(strict), ..*self }
+    }
+
+    /// Create a modified `FilesetOptions` with watch set.
+    pub fn watch(self: &Self, watch: bool) -> FilesetOptions {
+        FilesetOptions { watch: Some(watch), ..*self }
+    }
+
+    /// Create a modified `FilesetOptions` with order set.
+    pub fn order(self: &Self, order: FileOrder) -> FilesetOptions {
+        FilesetOptions { order: Some(order), ..*self }
+    }
+
     /// Open a `Fileset` with these options from the specified setfile. Note that you must include
     /// a `MergeFn` to combine colliding entries that have the same key.
+    ///
+    /// Opening fails if the setfile cannot be read or if any MTBL file it lists cannot be opened;
+    /// the error names the file and the reason.
     pub fn open_from_path<T: AsRef<Path>>(self: &Self,
                                           setfile: T,
-                                          merge_fn: Box<merger::MergeFn>)
-                                          -> Fileset {
-        let mut merge_fn = Box::new(merge_fn);
-        unsafe {
-            let mut opts = mtbl_sys::mtbl_fileset_options_init();
-            mtbl_sys::mtbl_fileset_options_set_merge_func(
-                // Wacky casting to get a void pointer for the C lib.
-                opts, merger::_merge_cb_shim, &mut (*merge_fn) as *mut _ as *mut c_void);
-            if let Some(reload_interval_seconds) = self.reload_interval_seconds {
-                mtbl_sys::mtbl_fileset_options_set_reload_interval(opts, reload_interval_seconds);
-            }
-            let c_path = CString::new(setfile.as_ref().to_str().unwrap().as_bytes()).unwrap();
-            let mtbl_fileset = mtbl_sys::mtbl_fileset_init(c_path.as_ptr(), opts);
-            let fileset = Fileset {
-                options: *self,
-                mtbl_fileset: mtbl_fileset,
-                mtbl_source: mtbl_sys::mtbl_fileset_source(mtbl_fileset),
-                _merge_fn: merge_fn,
-            };
-            mtbl_sys::mtbl_fileset_options_destroy(&mut opts);
-            fileset
+                                          merge_fn: Box<SyncMergeFn>)
+                                          -> IOResult<Fileset> {
+        self.open(setfile.as_ref(), Listing::Setfile, merge_fn)
+    }
+
+    /// Open a `Fileset` with these options that serves the files in a directory whose names
+    /// match `pattern`, in which `*` matches any run of characters and `?` any one character
+    /// (e.g. `"*.mtbl"`). Files whose names start with `.` or end with `.tmp` are ignored, so
+    /// that files can be written under such a name and renamed into place when complete.
+    ///
+    /// Files are merged in the configured [`order`](#structfield.order), and reloads pick up
+    /// files that have been added, removed or replaced. The set of files can't be changed
+    /// through the `Fileset`; change the directory instead.
+    pub fn open_dir<T: AsRef<Path>>(self: &Self,
+                                    dir: T,
+                                    pattern: &str,
+                                    merge_fn: Box<SyncMergeFn>)
+                                    -> IOResult<Fileset> {
+        self.open(dir.as_ref(), Listing::Dir(pattern.to_string()), merge_fn)
+    }
+
+    /// Open a `Fileset` of tombstone-encoded files with these options, merged
+    /// with [`tombstone::merge`](tombstone/fn.merge.html), that reads the
+    /// live keys and values.
+    pub fn open_tombstoned<T: AsRef<Path>>(self: &Self,
+                                           setfile: T)
+                                           -> IOResult<Tombstoned<Fileset>> {
+        let fileset = self.open_from_path(setfile, Box::new(tombstone::merge))?;
+        Ok(Tombstoned::new(fileset))
+    }
+
+    fn open(&self, path: &Path, listing: Listing, merge_fn: Box<SyncMergeFn>) -> IOResult<Fileset> {
+        let merge_fn: Arc<SyncMergeFn> = Arc::from(merge_fn);
+        let mut source = FilesetSource {
+            options: *self,
+            path: path.to_path_buf(),
+            listing,
+            current: RwLock::new(Arc::new(Generation::new(Vec::new(), &merge_fn))),
+            merge_fn,
+            updating: Mutex::new(()),
+            last_reload: Mutex::new(Instant::now()),
+            watcher: None,
+            notified: AtomicBool::new(false),
+            notifier: Notifier::new(),
+        };
+        if self.watch.unwrap_or(false) {
+            source.watcher = Some(Arc::new(Watcher::new()?));
+        }
+        let generation = source.load(None, true)?;
+        source.watch_files(&generation)?;
+        source.install(generation);
+        // Nothing can have registered for the initial set of files yet.
+        source.notifier.dispatch();
+        let source = Arc::new(source);
+        if let Some(ref watcher) = source.watcher {
+            spawn_watch_thread(&source, watcher.clone())?;
         }
+        Ok(Fileset {
+            options: *self,
+            source,
+        })
     }
 }
 
@@ -65,11 +173,22 @@ impl FilesetOptions {
 ///
 /// It acts like a [`Merger`](type.Merger.html) that watches that setfile for
 /// updates to a list of MTBL files. Note that paths in the setfile are
-/// *relative* paths from the directory of the setfile.
+/// *relative* paths from the directory of the setfile, and that colliding
+/// entries are merged in the order their files are listed.
+///
+/// Alternatively, a `Fileset` can serve the MTBL files in a directory, without
+/// a setfile; see [`open_dir`](#method.open_dir).
 ///
-/// `Fileset`s are not thread-safe because reloading, which happens
-/// automatically when reading, is not thread-safe--so only one thread can read
-/// from a `Fileset`.
+/// Each set of files that the `Fileset` serves is a generation: the listed
+/// files are opened and checked when it is loaded, so a missing or corrupt
+/// file is reported instead of being read, and reads merge the generation's
+/// `Reader`s. To make several reads against the same files, take a
+/// [`Snapshot`](struct.Snapshot.html).
+///
+/// A `Fileset` can be shared between threads. A reload or an update such as
+/// [`add_file`](#method.add_file) swaps in a new generation without waiting
+/// for reads; an iterator keeps reading the generation it started with, whose
+/// files stay open until the last of its iterators is dropped.
 ///
 /// To create a Fileset with non-default options, see
 /// [FilesetOptions](struct.FilesetOptions.html).
@@ -80,35 +199,232 @@ impl FilesetOptions {
 /// $ cp my-data.mtbl /tmp/my-data.mtbl
 /// $ echo "my-data.mtbl" >> /tmp/fs.mtbl-fileset
 /// ...
-/// let fileset = Fileset::open_from_path("/tmp/fs.mtbl-fileset", my_merge_fn);
+/// let fileset = Fileset::open_from_path("/tmp/fs.mtbl-fileset", my_merge_fn)?;
 /// ```
 pub struct Fileset {
     /// The options used to open this `Fileset`.
     pub options: FilesetOptions,
-    mtbl_fileset: *mut mtbl_sys::mtbl_fileset,
-    mtbl_source: *const mtbl_sys::mtbl_source,
-    _merge_fn: Box<Box<merger::MergeFn>>,
+    source: Arc<FilesetSource>,
 }
 
 impl Fileset {
     /// Open a `Fileset` from a path. Note that you must include a `MergeFn` to
     /// combine colliding entries (entries that have the same key).
-    pub fn open_from_path<T: AsRef<Path>>(setfile: T, merge_fn: Box<merger::MergeFn>) -> Fileset {
+    pub fn open_from_path<T: AsRef<Path>>(setfile: T,
+                                          merge_fn: Box<SyncMergeFn>)
+                                          -> IOResult<Fileset> {
         FilesetOptions::new().open_from_path(setfile, merge_fn)
     }
 
+    /// Open a `Fileset` that serves the files in a directory whose names match
+    /// `pattern`, merged in order of name. See
+    /// [`FilesetOptions::open_dir`](struct.FilesetOptions.html#method.open_dir).
+    pub fn open_dir<T: AsRef<Path>>(dir: T,
+                                    pattern: &str,
+                                    merge_fn: Box<SyncMergeFn>)
+                                    -> IOResult<Fileset> {
+        FilesetOptions::new().open_dir(dir, pattern, merge_fn)
+    }
+
+    /// Open a `Fileset` of tombstone-encoded files. See
+    /// [`FilesetOptions::open_tombstoned`](struct.FilesetOptions.html#method.open_tombstoned).
+    pub fn open_tombstoned<T: AsRef<Path>>(setfile: T) -> IOResult<Tombstoned<Fileset>> {
+        FilesetOptions::new().open_tombstoned(setfile)
+    }
+
     /// Reload the list of MTBL files (ignored if less than the configured reload
     /// interval has passed).
-    pub fn reload(&mut self) {
-        unsafe {
-            mtbl_sys::mtbl_fileset_reload(self.mtbl_fileset);
+    pub fn reload(&self) {
+        self.source.maybe_reload();
+    }
+
+    /// Add an MTBL file to the end of the set, so that it is merged after the
+    /// files already in it. Relative paths are relative to the setfile's
+    /// directory, as in the setfile itself.
+    ///
+    /// The file is opened before anything changes. Then the setfile is
+    /// rewritten atomically and the new file becomes visible to readers in a
+    /// single step. Adding a file that is already in the set does nothing.
+    pub fn add_file<T: AsRef<Path>>(&self, path: T) -> IOResult<()> {
+        self.source.update(|source| {
+            let path = source.resolve(path.as_ref());
+            let mut paths = source.paths();
+            if paths.contains(&path) {
+                return Ok(());
+            }
+            paths.push(path);
+            source.set_files(paths)
+        })
+    }
+
+    /// Remove an MTBL file from the set, returning whether it was in it.
+    /// Relative paths are relative to the setfile's directory.
+    ///
+    /// The setfile is rewritten atomically and the file stops being visible to
+    /// new reads in a single step. Iterators that are already open keep
+    /// reading it.
+    pub fn remove_file<T: AsRef<Path>>(&self, path: T) -> IOResult<bool> {
+        self.source.update(|source| {
+            let path = source.resolve(path.as_ref());
+            let mut paths = source.paths();
+            let len = paths.len();
+            paths.retain(|p| *p != path);
+            if paths.len() == len {
+                return Ok(false);
+            }
+            source.set_files(paths)?;
+            Ok(true)
+        })
+    }
+
+    /// Replace the whole set of MTBL files, in merge order. Relative paths are
+    /// relative to the setfile's directory.
+    ///
+    /// Every file is opened before anything changes. Then the setfile is
+    /// rewritten atomically and the new set becomes visible to readers in a
+    /// single step.
+    pub fn replace_files<T, I>(&self, paths: I) -> IOResult<()>
+        where T: AsRef<Path>,
+              I: IntoIterator<Item = T>
+    {
+        self.source.update(|source| {
+            let paths = paths.into_iter().map(|p| source.resolve(p.as_ref())).collect();
+            source.set_files(paths)
+        })
+    }
+
+    /// Replace a run of files that are next to each other in merge order with
+    /// a single file, such as one holding their merged contents. Relative paths
+    /// are relative to the setfile's directory.
+    ///
+    /// Returns false, changing nothing, if the files are no longer in the set
+    /// as that run. Otherwise the new file is opened, the setfile rewritten
+    /// atomically and the new set made visible to readers in a single step.
+    pub fn swap_files<T, U>(&self, old: &[T], new: U) -> IOResult<bool>
+        where T: AsRef<Path>,
+              U: AsRef<Path>
+    {
+        if old.is_empty() {
+            return Ok(false);
         }
+        self.source.update(|source| {
+            let old: Vec<PathBuf> = old.iter().map(|p| source.resolve(p.as_ref())).collect();
+            let mut paths = source.paths();
+            let start = match paths.windows(old.len()).position(|run| run == &old[..]) {
+                Some(start) => start,
+                None => return Ok(false),
+            };
+            paths.splice(start..start + old.len(), Some(source.resolve(new.as_ref())));
+            source.set_files(paths)?;
+            Ok(true)
+        })
+    }
+
+    /// The function used to combine values for colliding keys.
+    pub(crate) fn merge_fn(&self) -> Arc<SyncMergeFn> {
+        self.source.merge_fn.clone()
+    }
+
+    /// The directory that relative paths are resolved against.
+    pub(crate) fn dir(&self) -> PathBuf {
+        self.source.dir().to_path_buf()
+    }
+
+    /// Register a callback to run whenever a new set of files takes effect,
+    /// whether from a reload or from an update through this `Fileset`. It is
+    /// passed the paths of the files served before and after, in merge order.
+    ///
+    /// The lists can be equal, when a listed file was replaced in place.
+    /// Callbacks run one at a time, in the order of the changes, on the thread
+    /// that made the change (the watch thread, for reloads when watching). They
+    /// run once the change has been made and no locks are held, so they may
+    /// read from or update the `Fileset`; the callbacks for a change made by a
+    /// callback run after it returns.
+    pub fn on_change<F>(&self, callback: F)
+        where F: FnMut(&[PathBuf], &[PathBuf]) + Send + 'static
+    {
+        self.source.notifier.register(Box::new(callback));
+    }
+
+    /// Get a read-only view of the files currently being served (after
+    /// reloading, if a reload is due).
+    ///
+    /// The snapshot keeps its generation's files open and never reloads, so a
+    /// long scan of it sees a consistent set of files even while the `Fileset`
+    /// moves on to new ones. It shares the generation's `Reader`s with the
+    /// `Fileset`, so taking one opens nothing.
+    pub fn snapshot(&self) -> Snapshot {
+        self.source.maybe_reload();
+        self.source.snapshot()
+    }
+
+    /// Describe the MTBL files currently being served, in merge order.
+    ///
+    /// This does not reload the setfile, so it reports exactly what reads are
+    /// seeing until the next reload.
+    pub fn files(&self) -> Vec<FileInfo> {
+        self.source.current().files.iter().map(|f| f.info.clone()).collect()
+    }
+
+    /// The generation of the set of files being served. It starts at 1 when
+    /// the `Fileset` is opened and goes up by one every time a reload or an
+    /// update changes the set.
+    pub fn generation(&self) -> u64 {
+        self.source.current().number
+    }
+
+    /// Reload the list of MTBL files right away, regardless of the reload
+    /// interval.
+    ///
+    /// If a listed file cannot be opened, in strict mode the error is returned
+    /// and the previous set of files is kept; otherwise the file is skipped.
+    pub fn reload_now(&self) -> IOResult<()> {
+        self.source.update(|source| source.reload())
     }
 }
 
 impl Read for Fileset {
+    /// Always null: the generation being served can change, so reads go
+    /// through [`query`](#method.query), which holds on to one.
     fn raw_mtbl_source(&self) -> &*const mtbl_sys::mtbl_source {
-        &self.mtbl_source
+        &NO_MTBL_SOURCE
+    }
+
+    /// Reads the current generation (after reloading, if a reload is due),
+    /// which the iterator keeps open.
+    fn query(&self, query: Query) -> Iter<'_> {
+        self.source.maybe_reload();
+        let generation = self.source.current();
+        // The generation is kept alive for as long as the iterator, so the
+        // borrow can be erased.
+        let iter: Iter<'static> = unsafe { mem::transmute(generation.merger.query(query)) };
+        Iter::from_entries(Box::new(GenerationIter { iter, _generation: generation }))
+    }
+
+    /// The [`generation`](#method.generation) being served.
+    fn content_version(&self) -> Option<u64> {
+        Some(self.generation())
+    }
+
+    /// The union of the key ranges of the files being served, copied since
+    /// the set of files can change.
+    fn key_bounds(&self) -> Option<KeyBounds<'_>> {
+        self.source.maybe_reload();
+        let current = self.source.current();
+        let members = current.files.iter().map(|f| {
+            let bounds = f.info.key_range.as_ref().map(|(first, last)| {
+                (Cow::Borrowed(&first[..]), Cow::Borrowed(&last[..]))
+            });
+            (f.info.count_entries, bounds)
+        });
+        let (first, last) = union_bounds(members)?;
+        Some((Cow::Owned(first.into_owned()), Cow::Owned(last.into_owned())))
+    }
+
+    /// Answered from a [`snapshot`](#method.snapshot), whose `Reader`s can
+    /// seek.
+    fn nearest(&self, target: Nearest) -> Option<(Vec<u8>, Vec<u8>)> {
+        self.snapshot().nearest(target)
     }
 }
 
@@ -121,14 +437,652 @@ impl<'a> IntoIterator for &'a Fileset {
     }
 }
 
+/// A view of one generation of a `Fileset`'s files, from
+/// [`Fileset::snapshot`](struct.Fileset.html#method.snapshot).
+pub struct Snapshot {
+    generation: Arc<Generation>,
+}
+
+impl Snapshot {
+    /// Describe the MTBL files in this snapshot, in merge order.
+    pub fn files(&self) -> Vec<FileInfo> {
+        self.generation.files.iter().map(|f| f.info.clone()).collect()
+    }
+
+    /// The generation of the fileset that this snapshot was taken from.
+    pub fn generation(&self) -> u64 {
+        self.generation.number
+    }
+
+    /// The path, size in bytes and reader of each file, in merge order.
+    pub(crate) fn loaded_files(&self) -> Vec<(PathBuf, u64, Arc<Reader>)> {
+        self.generation
+            .files
+            .iter()
+            .map(|f| (f.path.clone(), f.identity.len, f.reader.clone()))
+            .collect()
+    }
+}
+
+impl Read for Snapshot {
+    fn raw_mtbl_source(&self) -> &*const mtbl_sys::mtbl_source {
+        self.generation.merger.raw_mtbl_source()
+    }
+
+    fn query(&self, query: Query) -> Iter<'_> {
+        self.generation.merger.query(query)
+    }
+
+    /// A snapshot never changes.
+    fn content_version(&self) -> Option<u64> {
+        Some(0)
+    }
+
+    /// The union of the key ranges of the snapshot's files.
+    fn key_bounds(&self) -> Option<KeyBounds<'_>> {
+        let files = &self.generation.files;
+        union_bounds(files.iter().map(|f| (f.info.count_entries, f.reader.key_bounds())))
+    }
+
+    fn get_sorted(&self, keys: &[&[u8]]) -> Vec<Option<Vec<u8>>> {
+        self.generation.merger.get_sorted(keys)
+    }
+
+    fn nearest(&self, target: Nearest) -> Option<(Vec<u8>, Vec<u8>)> {
+        self.generation.merger.nearest(target)
+    }
+}
+
+impl<'a> IntoIterator for &'a Snapshot {
+    type Item = (Vec<u8>, Vec<u8>);
+    type IntoIter = Iter<'a>;
+
+    fn into_iter(self) -> Iter<'a> {
+        self.iter()
+    }
+}
+
+/// A description of an MTBL file being served by a `Fileset`.
+#[derive(Clone,Debug,PartialEq)]
+pub struct FileInfo {
+    /// The path of the file, including the directory it is listed from.
+    pub path: PathBuf,
+    /// Total number of key-value entries.
+    pub count_entries: u64,
+    /// Total number of data blocks.
+    pub count_data_blocks: u64,
+    /// Total number of bytes consumed by data blocks.
+    pub bytes_data_blocks: u64,
+    /// Total number of bytes consumed by the index.
+    pub bytes_index_block: u64,
+    /// Total number of bytes of all keys.
+    pub bytes_keys: u64,
+    /// Total number of bytes of all values.
+    pub bytes_values: u64,
+    /// The compression used for data blocks.
+    pub compression_algorithm: CompressionType,
+    /// The first and last keys in the file, or `None` if it is empty.
+    pub key_range: Option<(Vec<u8>, Vec<u8>)>,
+}
+
 // TODO: Implement IntoIterator for Fileset? I'm not sure how to cleanly do the needed lifetime
 // stuff, though.
 
-impl Drop for Fileset {
-    fn drop(&mut self) {
-        unsafe {
-            mtbl_sys::mtbl_fileset_destroy(&mut self.mtbl_fileset);
+/// The modification time and size of a setfile or directory, used to tell
+/// whether it has changed since it was last loaded.
+type ListingStamp = (SystemTime, u64);
+
+/// What identifies the contents of an MTBL file, so that unchanged files can be
+/// kept open across reloads.
+#[derive(PartialEq)]
+pub(crate) struct FileIdentity {
+    dev: u64,
+    ino: u64,
+    mtime: (i64, i64),
+    len: u64,
+}
+
+impl FileIdentity {
+    pub(crate) fn of(path: &Path) -> IOResult<FileIdentity> {
+        let metadata = fs::metadata(path)?;
+        Ok(FileIdentity {
+            dev: metadata.dev(),
+            ino: metadata.ino(),
+            mtime: (metadata.mtime(), metadata.mtime_nsec()),
+            len: metadata.len(),
+        })
+    }
+}
+
+/// A loaded MTBL file.
+struct LoadedFile {
+    path: PathBuf,
+    identity: FileIdentity,
+    info: FileInfo,
+    reader: Arc<Reader>,
+}
+
+/// The smallest bounds containing the bounds of each of a set of files, given
+/// with their numbers of entries. Empty files are left out; if the bounds of
+/// any other file are unknown, so is the union.
+fn union_bounds<'a, I>(members: I) -> Option<KeyBounds<'a>>
+    where I: IntoIterator<Item = (u64, Option<KeyBounds<'a>>)>
+{
+    let mut union: Option<KeyBounds<'a>> = None;
+    for (count_entries, bounds) in members {
+        if count_entries == 0 {
+            continue;
+        }
+        let (first, last) = bounds?;
+        union = Some(match union {
+            None => (first, last),
+            Some((lo, hi)) => (lo.min(first), hi.max(last)),
+        });
+    }
+    union
+}
+
+/// Describe an MTBL file from a reader of it.
+fn file_info(path: &Path, reader: &Reader) -> FileInfo {
+    FileInfo {
+        path: path.to_path_buf(),
+        count_entries: reader.count_entries(),
+        count_data_blocks: reader.count_data_blocks(),
+        bytes_data_blocks: reader.bytes_data_blocks(),
+        bytes_index_block: reader.bytes_index_block(),
+        bytes_keys: reader.bytes_keys(),
+        bytes_values: reader.bytes_values(),
+        compression_algorithm: reader.compression_algorithm(),
+        key_range: reader.key_range(),
+    }
+}
+
+/// One loaded version of the list of files, which stay open as long as it is
+/// being served or read.
+struct Generation {
+    number: u64,
+    stamp: Option<ListingStamp>,
+    files: Vec<LoadedFile>,
+    /// A merger of the files' readers, in merge order.
+    merger: Merger<Arc<Reader>>,
+}
+
+impl Generation {
+    fn new(files: Vec<LoadedFile>, merge_fn: &Arc<SyncMergeFn>) -> Generation {
+        let merge_fn = merge_fn.clone();
+        let merger = Merger::new(files.iter().map(|f| f.reader.clone()).collect(),
+                                 move |key, val0, val1| merge_fn(key, val0, val1));
+        Generation {
+            number: 0,
+            stamp: None,
+            files,
+            merger,
+        }
+    }
+}
+
+/// Where a `Fileset`'s list of files comes from.
+enum Listing {
+    /// A setfile, listing one path per line.
+    Setfile,
+    /// The entries of a directory whose names match a pattern.
+    Dir(String),
+}
+
+/// A callback run when a new set of files takes effect.
+type ChangeFn = dyn FnMut(&[PathBuf], &[PathBuf]) + Send;
+
+/// The state behind a `Fileset`.
+struct FilesetSource {
+    options: FilesetOptions,
+    // The setfile or directory.
+    path: PathBuf,
+    listing: Listing,
+    merge_fn: Arc<SyncMergeFn>,
+    current: RwLock<Arc<Generation>>,
+    // Held while changing the set of files, so that concurrent reloads and
+    // updates don't undo each other.
+    updating: Mutex<()>,
+    last_reload: Mutex<Instant>,
+    watcher: Option<Arc<Watcher>>,
+    // Whether the watcher has seen a change that hasn't been reloaded yet.
+    notified: AtomicBool,
+    notifier: Notifier,
+}
+
+/// Runs the `on_change` callbacks for the changes made to a `Fileset`, after
+/// the locks used to make them have been released.
+struct Notifier {
+    state: Mutex<NotifierState>,
+}
+
+struct NotifierState {
+    callbacks: Vec<Box<ChangeFn>>,
+    // The changes not yet dispatched, as the old and new paths.
+    queue: VecDeque<(Vec<PathBuf>, Vec<PathBuf>)>,
+    // Whether some thread is running the callbacks.
+    dispatching: bool,
+}
+
+impl Notifier {
+    fn new() -> Notifier {
+        Notifier {
+            state: Mutex::new(NotifierState {
+                callbacks: Vec::new(),
+                queue: VecDeque::new(),
+                dispatching: false,
+            }),
+        }
+    }
+
+    fn register(&self, callback: Box<ChangeFn>) {
+        self.state.lock().unwrap().callbacks.push(callback);
+    }
+
+    /// Queue a change for the next `dispatch`.
+    fn push(&self, old: Vec<PathBuf>, new: Vec<PathBuf>) {
+        self.state.lock().unwrap().queue.push_back((old, new));
+    }
+
+    /// Run the callbacks for the queued changes, in order. If another thread
+    /// is already doing so, including a callback on this thread that made a
+    /// change of its own, that thread runs them instead.
+    fn dispatch(&self) {
+        let mut state = self.state.lock().unwrap();
+        if state.dispatching {
+            return;
+        }
+        while let Some((old, new)) = state.queue.pop_front() {
+            state.dispatching = true;
+            let mut callbacks = mem::take(&mut state.callbacks);
+            drop(state);
+            for callback in &mut callbacks {
+                callback(&old, &new);
+            }
+            state = self.state.lock().unwrap();
+            // Keep any callbacks registered in the meantime, after the
+            // earlier ones.
+            callbacks.append(&mut state.callbacks);
+            state.callbacks = callbacks;
+            state.dispatching = false;
+        }
+    }
+}
+
+fn listing_stamp(path: &Path) -> IOResult<ListingStamp> {
+    let metadata = fs::metadata(path)?;
+    Ok((metadata.modified()?, metadata.len()))
+}
+
+/// Read the paths listed in a setfile, resolved relative to its directory.
+/// Paths may be arbitrary bytes; empty lines are ignored.
+fn read_setfile(setfile: &Path) -> IOResult<Vec<PathBuf>> {
+    let mut contents = Vec::new();
+    File::open(setfile).and_then(|mut f| f.read_to_end(&mut contents))?;
+    let dir = setfile.parent().unwrap_or_else(|| Path::new(""));
+    Ok(contents.split(|&b| b == b'\n')
+               .filter(|line| !line.is_empty())
+               .map(|line| dir.join(OsStr::from_bytes(line)))
+               .collect())
+}
+
+/// Whether a file name matches a pattern in which `*` matches any sequence of
+/// bytes and `?` any one byte.
+fn matches_pattern(pattern: &[u8], name: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some((&b'*', rest)) => (0..name.len() + 1).any(|i| matches_pattern(rest, &name[i..])),
+        Some((&b'?', rest)) => !name.is_empty() && matches_pattern(rest, &name[1..]),
+        Some((&c, rest)) => name.first() == Some(&c) && matches_pattern(rest, &name[1..]),
+    }
+}
+
+/// Whether a file name marks a file that is still being written.
+fn is_temporary(name: &[u8]) -> bool {
+    name.starts_with(b".") || name.ends_with(b".tmp")
+}
+
+/// List the files in a directory whose names match a pattern, in merge order.
+fn read_dir(dir: &Path, pattern: &str, order: FileOrder) -> IOResult<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let name = name.as_bytes();
+        if is_temporary(name) || !matches_pattern(pattern.as_bytes(), name) {
+            continue;
+        }
+        // Follow symlinks, and skip anything that vanished in the meantime.
+        let metadata = match fs::metadata(entry.path()) {
+            Ok(metadata) => metadata,
+            Err(_) => continue,
+        };
+        if metadata.is_file() {
+            files.push(((metadata.mtime(), metadata.mtime_nsec()), entry.path()));
+        }
+    }
+    match order {
+        FileOrder::Name => files.sort_by(|a, b| a.1.cmp(&b.1)),
+        FileOrder::Modified => files.sort(),
+    }
+    Ok(files.into_iter().map(|(_, path)| path).collect())
+}
+
+impl FilesetSource {
+    fn reload_interval(&self) -> Duration {
+        let seconds = self.options.reload_interval_seconds.unwrap_or(DEFAULT_RELOAD_INTERVAL_SECONDS);
+        Duration::from_secs(u64::from(seconds))
+    }
+
+    /// A description of the setfile or directory, for error messages.
+    fn describe(&self) -> String {
+        match self.listing {
+            Listing::Setfile => format!("setfile {}", self.path.display()),
+            Listing::Dir(_) => format!("directory {}", self.path.display()),
+        }
+    }
+
+    /// The paths of the MTBL files currently listed, in merge order.
+    fn list(&self) -> IOResult<Vec<PathBuf>> {
+        match self.listing {
+            Listing::Setfile => read_setfile(&self.path),
+            Listing::Dir(ref pattern) => {
+                read_dir(&self.path, pattern, self.options.order.unwrap_or(FileOrder::Name))
+            }
+        }
+    }
+
+    /// Load the files currently listed, reusing any that are unchanged from
+    /// `previous`. If `strict`, fail on the first file that cannot be opened;
+    /// otherwise skip it.
+    fn load(&self, previous: Option<&Generation>, strict: bool) -> IOResult<Generation> {
+        let listing_error = |e: Error| {
+            Error::new(e.kind(),
+                       format!("failed to read MTBL {}: {}", self.describe(), e))
+        };
+        let stamp = listing_stamp(&self.path).map_err(&listing_error)?;
+        let paths = self.list().map_err(&listing_error)?;
+        let mut generation = self.open_files(paths, previous, strict)?;
+        generation.stamp = Some(stamp);
+        Ok(generation)
+    }
+
+    /// Open and check a generation of MTBL files, reusing the readers of the
+    /// ones that are unchanged from `previous`. If `strict`, fail on the first file that
+    /// cannot be opened; otherwise skip it.
+    fn open_files(&self,
+                  paths: Vec<PathBuf>,
+                  previous: Option<&Generation>,
+                  strict: bool)
+                  -> IOResult<Generation> {
+        let mut files = Vec::with_capacity(paths.len());
+        for path in paths {
+            let loaded = FileIdentity::of(&path).and_then(|identity| {
+                let reused = previous.and_then(|p| {
+                    p.files.iter().find(|f| f.path == path && f.identity == identity)
+                });
+                let (info, reader) = match reused {
+                    Some(file) => (file.info.clone(), file.reader.clone()),
+                    None => {
+                        let reader = Reader::open_from_path(&path)?;
+                        (file_info(&path, &reader), Arc::new(reader))
+                    }
+                };
+                Ok(LoadedFile { path: path.clone(), identity, info, reader })
+            });
+            match loaded {
+                Ok(file) => files.push(file),
+                Err(e) => {
+                    if strict {
+                        return Err(Error::new(e.kind(),
+                                              format!("failed to open MTBL file {} listed in \
+                                                       {}: {}",
+                                                      path.display(),
+                                                      self.describe(),
+                                                      e)));
+                    }
+                }
+            }
+        }
+        Ok(Generation::new(files, &self.merge_fn))
+    }
+
+    /// Resolve a path the way entries in the setfile are resolved: relative
+    /// to the setfile's directory (or to the directory being served).
+    fn resolve(&self, path: &Path) -> PathBuf {
+        self.dir().join(path)
+    }
+
+    /// The directory containing the setfile, or the directory being served.
+    fn dir(&self) -> &Path {
+        match self.listing {
+            Listing::Setfile => self.path.parent().unwrap_or_else(|| Path::new("")),
+            Listing::Dir(_) => &self.path,
+        }
+    }
+
+    /// Atomically and durably replace the setfile with one listing `paths`,
+    /// by writing a temporary file next to it, renaming it into place and
+    /// syncing the directory.
+    fn write_setfile(&self, paths: &[PathBuf]) -> IOResult<ListingStamp> {
+        static NEXT_TEMP: AtomicUsize = AtomicUsize::new(0);
+        let dir = self.dir();
+        let mut contents = Vec::new();
+        for path in paths {
+            let entry = path.strip_prefix(dir).unwrap_or(path);
+            let entry = entry.as_os_str().as_bytes();
+            if entry.contains(&b'\n') {
+                return Err(Error::new(ErrorKind::InvalidInput,
+                                      format!("MTBL file name {} contains a newline",
+                                              path.display())));
+            }
+            contents.extend_from_slice(entry);
+            contents.push(b'\n');
+        }
+        let mut temp_name = OsString::from(".");
+        temp_name.push(self.path.file_name().unwrap_or_else(|| OsStr::new("setfile")));
+        // Unique among the filesets of this process too, which may share the
+        // setfile.
+        temp_name.push(format!(".{}.{}.tmp",
+                               process::id(),
+                               NEXT_TEMP.fetch_add(1, Ordering::SeqCst)));
+        let temp = dir.join(temp_name);
+        let written = File::create(&temp).and_then(|mut f| {
+            f.write_all(&contents)?;
+            f.sync_all()
+        });
+        if let Err(e) = written.and_then(|_| fs::rename(&temp, &self.path)) {
+            let _ = fs::remove_file(&temp);
+            return Err(e);
+        }
+        // Make the rename itself durable.
+        let synced_dir = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+        File::open(synced_dir)?.sync_all()?;
+        listing_stamp(&self.path)
+    }
+
+    /// Make `paths` the set of MTBL files, both on disk and for readers. Every
+    /// file must open; otherwise nothing changes. The caller must hold the
+    /// `updating` lock.
+    fn set_files(&self, paths: Vec<PathBuf>) -> IOResult<()> {
+        if let Listing::Dir(_) = self.listing {
+            return Err(Error::new(ErrorKind::Unsupported,
+                                  format!("the files of MTBL {} can only be changed in the \
+                                           directory",
+                                          self.describe())));
+        }
+        let previous = self.current();
+        let mut generation = self.open_files(paths, Some(&previous), true)?;
+        let paths: Vec<PathBuf> = generation.files.iter().map(|f| f.path.clone()).collect();
+        generation.stamp = Some(self.write_setfile(&paths)?);
+        self.install(generation);
+        Ok(())
+    }
+
+    /// Start serving a new generation of files, and tell the callbacks. Reads
+    /// that are already in progress keep the previous generation. The caller
+    /// must hold the `updating` lock.
+    fn install(&self, mut generation: Generation) {
+        let previous = self.current();
+        generation.number = previous.number + 1;
+        let paths: Vec<PathBuf> = generation.files.iter().map(|f| f.path.clone()).collect();
+        *self.current.write().unwrap() = Arc::new(generation);
+        // The files have already been checked, so watching them is
+        // best-effort.
+        let _ = self.watch_files(&self.current());
+        let previous_paths: Vec<PathBuf> = previous.files.iter().map(|f| f.path.clone()).collect();
+        self.notifier.push(previous_paths, paths);
+    }
+
+    /// If watching, watch the setfile's directory (or the directory being
+    /// served) and the directories of a generation's files, and no others.
+    fn watch_files(&self, generation: &Generation) -> IOResult<()> {
+        if let Some(ref watcher) = self.watcher {
+            let mut dirs: HashSet<PathBuf> = generation.files
+                                                       .iter()
+                                                       .map(|f| {
+                                                           f.path
+                                                            .parent()
+                                                            .unwrap_or_else(|| Path::new(""))
+                                                            .to_path_buf()
+                                                       })
+                                                       .collect();
+            dirs.insert(self.dir().to_path_buf());
+            watcher.set_dirs(&dirs)?;
+        }
+        Ok(())
+    }
+
+    /// Run `f` holding the `updating` lock, then run the callbacks for the
+    /// changes it made once the lock is released.
+    fn update<T, F>(&self, f: F) -> IOResult<T>
+        where F: FnOnce(&FilesetSource) -> IOResult<T>
+    {
+        let result = {
+            let _updating = self.updating.lock().unwrap();
+            f(self)
+        };
+        self.notifier.dispatch();
+        result
+    }
+
+    /// Whether the setfile or directory, or any of the files it lists, differ
+    /// from what is being served.
+    fn is_stale(&self) -> bool {
+        let current = self.current();
+        if listing_stamp(&self.path).ok() != current.stamp {
+            return true;
         }
+        let listed = match self.list() {
+            Ok(listed) => listed,
+            Err(_) => return true,
+        };
+        // A listed file that was skipped because it could not be opened may
+        // have appeared since.
+        listed.len() != current.files.len() ||
+        listed.iter().zip(&current.files).any(|(path, file)| {
+            *path != file.path || FileIdentity::of(path).ok().as_ref() != Some(&file.identity)
+        })
+    }
+
+    /// Reload the setfile now. The caller must hold the `updating` lock.
+    fn reload(&self) -> IOResult<()> {
+        let previous = self.current();
+        let generation = self.load(Some(&previous), self.options.strict.unwrap_or(false))?;
+        self.install(generation);
+        self.reloaded();
+        Ok(())
+    }
+
+    /// Note that the setfile has just been reloaded or found to be unchanged.
+    fn reloaded(&self) {
+        *self.last_reload.lock().unwrap() = Instant::now();
+        self.notified.store(false, Ordering::SeqCst);
+    }
+
+    /// Reload the setfile if the reload interval has passed (or, if watching,
+    /// the watcher has seen a change) and it has changed, then run the
+    /// callbacks. A failed reload keeps the current files. If another thread
+    /// is already reloading or updating the files, this leaves it to that
+    /// thread.
+    fn maybe_reload(&self) {
+        if self.watcher.is_some() {
+            if !self.notified.load(Ordering::SeqCst) {
+                return;
+            }
+        } else if self.last_reload.lock().unwrap().elapsed() < self.reload_interval() {
+            return;
+        }
+        {
+            let _updating = match self.updating.try_lock() {
+                Ok(updating) => updating,
+                Err(_) => return,
+            };
+            // Events can come from unrelated files in the same directories,
+            // or from this fileset's own updates.
+            if !self.is_stale() {
+                self.reloaded();
+                return;
+            }
+            if self.reload().is_err() {
+                self.reloaded();
+            }
+        }
+        self.notifier.dispatch();
+    }
+
+    /// The paths of the files currently being served.
+    fn paths(&self) -> Vec<PathBuf> {
+        self.current().files.iter().map(|f| f.path.clone()).collect()
+    }
+
+    /// Get the current generation, without reloading.
+    fn current(&self) -> Arc<Generation> {
+        self.current.read().unwrap().clone()
+    }
+
+    /// Take a snapshot of the current generation.
+    fn snapshot(&self) -> Snapshot {
+        Snapshot { generation: self.current() }
+    }
+}
+
+/// Start the thread that reloads a watching `Fileset` when its watcher sees a
+/// change, without waiting for a read. The thread exits once the `Fileset` is
+/// dropped.
+fn spawn_watch_thread(source: &Arc<FilesetSource>, watcher: Arc<Watcher>) -> IOResult<()> {
+    let source = Arc::downgrade(source);
+    thread::Builder::new().name("mtbl-fileset-watch".into()).spawn(move || {
+        loop {
+            let changed = watcher.wait(WATCH_POLL_INTERVAL);
+            let source = match source.upgrade() {
+                Some(source) => source,
+                None => return,
+            };
+            if changed {
+                source.notified.store(true, Ordering::SeqCst);
+            }
+            // A reload left to another thread that was updating the files is
+            // retried on the next pass.
+            source.maybe_reload();
+        }
+    })?;
+    Ok(())
+}
+
+/// An iterator over a generation that keeps it open, so that a reload cannot
+/// close the files it is reading.
+struct GenerationIter {
+    // Declared before `_generation` so that it is dropped first.
+    iter: Iter<'static>,
+    _generation: Arc<Generation>,
+}
+
+impl Iterator for GenerationIter {
+    type Item = (Vec<u8>, Vec<u8>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.iter.next()
     }
 }
 
@@ -136,12 +1090,22 @@ impl Drop for Fileset {
 mod tests {
     extern crate tempfile;
     use self::tempfile::NamedTempFile;
+    use std::ffi::OsStr;
+    use std::fs::{self, File};
     use std::io::Write;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::PathBuf;
+    use std::sync::{mpsc, Arc, Mutex};
+    use std::thread;
+    use std::time::{Duration, Instant, UNIX_EPOCH};
 
-    use fileset::FilesetOptions;
+    use fileset::{FileOrder, Fileset, FilesetOptions, WATCH_POLL_INTERVAL};
+    use merger::Merger;
     use reader::Read;
+    use test_util::{create_mtbl, write_setfile, TempDir};
     use writer;
     use writer::Write as iWrite;
+    use writer::WriterOptions;
 
     #[test]
     fn test_fileset() {
@@ -170,7 +1134,8 @@ mod tests {
         let fileset = FilesetOptions::new()
                           .reload_interval_seconds(50)
                           .open_from_path(fileset_f.path(),
-                                          Box::new(|_key, _val0, _val1| "wat".as_bytes().to_vec()));
+                                          Box::new(|_key, _val0, _val1| "wat".as_bytes().to_vec()))
+                          .unwrap();
         assert_eq!(fileset.get("a"), None);
         // "one" collides
         assert_eq!(fileset.get("one"), Some("wat".as_bytes().to_vec()));
@@ -181,4 +1146,376 @@ mod tests {
             println!("{} {}", k.len(), v.len());
         }
     }
+
+    #[test]
+    fn test_open_errors() {
+        let dir = TempDir::new();
+        let setfile = dir.path().join("fs.mtbl-fileset");
+        let merge_fn = || Box::new(Merger::merge_choose_last_value);
+        assert!(Fileset::open_from_path(&setfile, merge_fn()).is_err());
+
+        create_mtbl(dir.path().join("good.mtbl"), WriterOptions::new(), &[("one", "Hello")]);
+        write_setfile(&setfile, &["good.mtbl", "missing.mtbl"]);
+        let err = Fileset::open_from_path(&setfile, merge_fn()).err().unwrap();
+        assert!(err.to_string().contains("missing.mtbl"));
+
+        File::create(dir.path().join("corrupt.mtbl")).unwrap().write_all(b"not an mtbl").unwrap();
+        write_setfile(&setfile, &["good.mtbl", "corrupt.mtbl"]);
+        let err = Fileset::open_from_path(&setfile, merge_fn()).err().unwrap();
+        assert!(err.to_string().contains("corrupt.mtbl"));
+    }
+
+    #[test]
+    fn test_non_utf8_paths() {
+        let dir = TempDir::new();
+        let name = OsStr::from_bytes(b"data-\xff.mtbl");
+        create_mtbl(dir.path().join(name), WriterOptions::new(), &[("one", "Hello")]);
+        let setfile = dir.path().join(OsStr::from_bytes(b"fs-\xfe.mtbl-fileset"));
+        write_setfile(&setfile, &[name]);
+        let fileset = Fileset::open_from_path(&setfile,
+                                              Box::new(Merger::merge_choose_last_value))
+                          .unwrap();
+        assert_eq!(fileset.get("one"), Some("Hello".as_bytes().to_vec()));
+    }
+
+    #[test]
+    fn test_strict_reload() {
+        let dir = TempDir::new();
+        let setfile = dir.path().join("fs.mtbl-fileset");
+        create_mtbl(dir.path().join("a.mtbl"), WriterOptions::new(), &[("one", "Hello")]);
+        create_mtbl(dir.path().join("b.mtbl"), WriterOptions::new(), &[("two", "world")]);
+        write_setfile(&setfile, &["a.mtbl"]);
+        let strict = FilesetOptions::new()
+                         .strict(true)
+                         .open_from_path(&setfile, Box::new(Merger::merge_choose_last_value))
+                         .unwrap();
+        let lenient = FilesetOptions::new()
+                          .open_from_path(&setfile, Box::new(Merger::merge_choose_last_value))
+                          .unwrap();
+
+        write_setfile(&setfile,
+                      &["a.mtbl", "b.mtbl", "missing.mtbl"]);
+        let err = strict.reload_now().err().unwrap();
+        assert!(err.to_string().contains("missing.mtbl"));
+        // The strict fileset keeps serving the previous set of files.
+        assert_eq!(strict.get("one"), Some("Hello".as_bytes().to_vec()));
+        assert_eq!(strict.get("two"), None);
+        // The lenient fileset serves what it can.
+        lenient.reload_now().unwrap();
+        assert_eq!(lenient.get("one"), Some("Hello".as_bytes().to_vec()));
+        assert_eq!(lenient.get("two"), Some("world".as_bytes().to_vec()));
+    }
+
+    #[test]
+    fn test_membership() {
+        let dir = TempDir::new();
+        let setfile = dir.path().join("fs.mtbl-fileset");
+        create_mtbl(dir.path().join("a.mtbl"), WriterOptions::new(), &[("one", "Hello")]);
+        create_mtbl(dir.path().join("b.mtbl"),
+                    WriterOptions::new(),
+                    &[("one", "blue"), ("two", "world")]);
+        write_setfile(&setfile, &["a.mtbl"]);
+        let fileset = Fileset::open_from_path(&setfile,
+                                              Box::new(Merger::merge_choose_last_value))
+                          .unwrap();
+
+        fileset.add_file("b.mtbl").unwrap();
+        fileset.add_file(dir.path().join("b.mtbl")).unwrap();
+        assert_eq!(fileset.get("one"), Some("blue".as_bytes().to_vec()));
+        assert_eq!(fs::read(&setfile).unwrap(), b"a.mtbl\nb.mtbl\n".to_vec());
+
+        assert!(fileset.add_file("missing.mtbl").is_err());
+        assert_eq!(fs::read(&setfile).unwrap(), b"a.mtbl\nb.mtbl\n".to_vec());
+
+        fileset.replace_files(Vec::<PathBuf>::new()).unwrap();
+        assert_eq!(fileset.get("one"), None);
+
+        fileset.replace_files(["b.mtbl", "a.mtbl"]).unwrap();
+        assert_eq!(fileset.get("one"), Some("Hello".as_bytes().to_vec()));
+        assert!(fileset.remove_file("a.mtbl").unwrap());
+        assert!(!fileset.remove_file("a.mtbl").unwrap());
+        assert_eq!(fileset.get("one"), Some("blue".as_bytes().to_vec()));
+
+        let reopened = Fileset::open_from_path(&setfile,
+                                               Box::new(Merger::merge_choose_last_value))
+                           .unwrap();
+        assert_eq!(reopened.get("one"), Some("blue".as_bytes().to_vec()));
+        assert_eq!(reopened.get("two"), Some("world".as_bytes().to_vec()));
+
+        // Two filesets of the same setfile can update it at once.
+        thread::scope(|s| {
+            s.spawn(|| fileset.add_file("a.mtbl").unwrap());
+            s.spawn(|| reopened.remove_file("b.mtbl").unwrap());
+        });
+        assert!(fs::read_dir(dir.path())
+                    .unwrap()
+                    .all(|entry| !entry.unwrap().file_name().as_bytes().ends_with(b".tmp")));
+    }
+
+    #[test]
+    fn test_files() {
+        let dir = TempDir::new();
+        let setfile = dir.path().join("fs.mtbl-fileset");
+        create_mtbl(dir.path().join("a.mtbl"),
+                    WriterOptions::new(),
+                    &[("one", "Hello"), ("two", "world")]);
+        create_mtbl(dir.path().join("b.mtbl"), WriterOptions::new(), &[] as &[(&str, &str)]);
+        write_setfile(&setfile, &["a.mtbl"]);
+        let fileset = Fileset::open_from_path(&setfile,
+                                              Box::new(Merger::merge_choose_last_value))
+                          .unwrap();
+        assert_eq!(fileset.generation(), 1);
+        let files = fileset.files();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].path, dir.path().join("a.mtbl"));
+        assert_eq!(files[0].count_entries, 2);
+        assert_eq!(files[0].bytes_keys, 6);
+        assert_eq!(files[0].bytes_values, 10);
+        assert_eq!(files[0].key_range,
+                   Some(("one".as_bytes().to_vec(), "two".as_bytes().to_vec())));
+
+        fileset.add_file("b.mtbl").unwrap();
+        assert_eq!(fileset.generation(), 2);
+        let files = fileset.files();
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[1].count_entries, 0);
+        assert_eq!(files[1].key_range, None);
+
+        // The bounds of the set are the union of its files', leaving out the
+        // empty one.
+        create_mtbl(dir.path().join("c.mtbl"), WriterOptions::new(), &[("a", "x"), ("b", "y")]);
+        fileset.add_file("c.mtbl").unwrap();
+        let bounds = Some(("a".as_bytes().into(), "two".as_bytes().into()));
+        assert_eq!(fileset.key_bounds(), bounds);
+        assert_eq!(fileset.snapshot().key_bounds(), bounds);
+        fileset.remove_file("c.mtbl").unwrap();
+        let files = fileset.files();
+
+        fileset.reload_now().unwrap();
+        assert_eq!(fileset.generation(), 5);
+        assert_eq!(fileset.files(), files);
+    }
+
+    #[test]
+    fn test_watch() {
+        let dir = TempDir::new();
+        let setfile = dir.path().join("fs.mtbl-fileset");
+        create_mtbl(dir.path().join("a.mtbl"), WriterOptions::new(), &[("one", "Hello")]);
+        create_mtbl(dir.path().join("b.mtbl"), WriterOptions::new(), &[("one", "blue")]);
+        write_setfile(&setfile, &["a.mtbl"]);
+        let fileset = FilesetOptions::new()
+                          .reload_interval_seconds(3600)
+                          .watch(true)
+                          .open_from_path(&setfile, Box::new(Merger::merge_choose_last_value))
+                          .unwrap();
+        let fileset = Arc::new(fileset);
+        // Each change, with the value of "one" read from the callback.
+        let changes = Arc::new(Mutex::new(Vec::new()));
+        {
+            let changes = changes.clone();
+            let reader = Arc::downgrade(&fileset);
+            fileset.on_change(move |old, new| {
+                let value = reader.upgrade().and_then(|fileset| fileset.get("one"));
+                changes.lock().unwrap().push((old.len(), new.len(), value));
+            });
+        }
+        let wait_for_changes = |count: usize| {
+            let deadline = Instant::now() + Duration::from_secs(10);
+            while changes.lock().unwrap().len() < count && Instant::now() < deadline {
+                thread::sleep(Duration::from_millis(10));
+            }
+            changes.lock().unwrap().clone()
+        };
+
+        // Changes are picked up without anything reading the fileset.
+        write_setfile(&setfile, &["a.mtbl", "b.mtbl"]);
+        assert_eq!(wait_for_changes(1), vec![(1, 2, Some(b"blue".to_vec()))]);
+        assert_eq!(fileset.get("one"), Some("blue".as_bytes().to_vec()));
+
+        // Replacing a listed file is noticed too.
+        let replacement = dir.path().join(".b.mtbl.tmp");
+        create_mtbl(&replacement, WriterOptions::new(), &[("one", "green")]);
+        fs::rename(&replacement, dir.path().join("b.mtbl")).unwrap();
+        assert_eq!(wait_for_changes(2)[1], (2, 2, Some(b"green".to_vec())));
+
+        // Updates through the fileset don't cause a second reload.
+        fileset.remove_file("b.mtbl").unwrap();
+        assert_eq!(wait_for_changes(3)[2], (2, 1, Some(b"Hello".to_vec())));
+        thread::sleep(WATCH_POLL_INTERVAL * 2);
+        assert_eq!(changes.lock().unwrap().len(), 3);
+        assert_eq!(fileset.generation(), 4);
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let dir = TempDir::new();
+        let setfile = dir.path().join("fs.mtbl-fileset");
+        create_mtbl(dir.path().join("a.mtbl"),
+                    WriterOptions::new(),
+                    &[("one", "Hello"), ("two", "world")]);
+        create_mtbl(dir.path().join("b.mtbl"), WriterOptions::new(), &[("one", "blue")]);
+        write_setfile(&setfile, &["a.mtbl", "b.mtbl"]);
+        let fileset = Fileset::open_from_path(&setfile,
+                                              Box::new(Merger::merge_choose_last_value))
+                          .unwrap();
+        let snapshot = fileset.snapshot();
+        let mut it = snapshot.iter();
+        assert_eq!(it.next(), Some(("one".as_bytes().to_vec(), "blue".as_bytes().to_vec())));
+
+        fileset.remove_file("b.mtbl").unwrap();
+        fs::remove_file(dir.path().join("b.mtbl")).unwrap();
+        assert_eq!(fileset.get("one"), Some("Hello".as_bytes().to_vec()));
+        assert_eq!(snapshot.get("one"), Some("blue".as_bytes().to_vec()));
+        assert_eq!(it.next(), Some(("two".as_bytes().to_vec(), "world".as_bytes().to_vec())));
+        assert_eq!(it.next(), None);
+        assert_eq!(snapshot.generation(), 1);
+        assert_eq!(snapshot.files().len(), 2);
+        assert_eq!(fileset.snapshot().files().len(), 1);
+    }
+
+    #[test]
+    fn test_iter_keeps_generation() {
+        let dir = TempDir::new();
+        let setfile = dir.path().join("fs.mtbl-fileset");
+        create_mtbl(dir.path().join("a.mtbl"),
+                    WriterOptions::new(),
+                    &[("one", "Hello"), ("two", "world")]);
+        create_mtbl(dir.path().join("b.mtbl"), WriterOptions::new(), &[("one", "blue")]);
+        write_setfile(&setfile, &["a.mtbl", "b.mtbl"]);
+        let fileset = Fileset::open_from_path(&setfile,
+                                              Box::new(Merger::merge_choose_last_value))
+                          .unwrap();
+        let mut it = fileset.iter();
+        let mut range = fileset.get_range("two", "zz");
+        assert_eq!(it.next(), Some(("one".as_bytes().to_vec(), "blue".as_bytes().to_vec())));
+
+        // Updates on the same thread go ahead while the iterators are open,
+        // and the iterators go on reading the files they started with, even
+        // once one has been deleted.
+        fileset.remove_file("b.mtbl").unwrap();
+        fs::remove_file(dir.path().join("b.mtbl")).unwrap();
+        fileset.replace_files(Vec::<PathBuf>::new()).unwrap();
+        assert_eq!(fileset.generation(), 3);
+        assert_eq!(fileset.get("one"), None);
+        assert_eq!(it.next(), Some(("two".as_bytes().to_vec(), "world".as_bytes().to_vec())));
+        assert_eq!(it.next(), None);
+        assert_eq!(range.next(), Some(("two".as_bytes().to_vec(), "world".as_bytes().to_vec())));
+        assert_eq!(fileset.iter().count(), 0);
+    }
+
+    #[test]
+    fn test_threads() {
+        let dir = TempDir::new();
+        let setfile = dir.path().join("fs.mtbl-fileset");
+        create_mtbl(dir.path().join("a.mtbl"), WriterOptions::new(), &[("one", "Hello")]);
+        create_mtbl(dir.path().join("b.mtbl"), WriterOptions::new(), &[("two", "world")]);
+        write_setfile(&setfile, &["a.mtbl"]);
+        let fileset = Arc::new(FilesetOptions::new()
+                                   .reload_interval_seconds(0)
+                                   .open_from_path(&setfile,
+                                                   Box::new(Merger::merge_choose_last_value))
+                                   .unwrap());
+        let mut threads = Vec::new();
+        for _ in 0..8 {
+            let fileset = fileset.clone();
+            threads.push(thread::spawn(move || {
+                for _ in 0..100 {
+                    assert_eq!(fileset.get("one"), Some("Hello".as_bytes().to_vec()));
+                    fileset.reload();
+                }
+            }));
+        }
+        fileset.add_file("b.mtbl").unwrap();
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert_eq!(fileset.get("two"), Some("world".as_bytes().to_vec()));
+        assert_eq!(fileset.files().len(), 2);
+    }
+
+    #[test]
+    fn test_reload_during_scan() {
+        let dir = TempDir::new();
+        let setfile = dir.path().join("fs.mtbl-fileset");
+        create_mtbl(dir.path().join("a.mtbl"),
+                    WriterOptions::new(),
+                    &[("one", "Hello"), ("two", "world")]);
+        create_mtbl(dir.path().join("b.mtbl"), WriterOptions::new(), &[("one", "blue")]);
+        create_mtbl(dir.path().join("c.mtbl"), WriterOptions::new(), &[("two", "green")]);
+        write_setfile(&setfile, &["a.mtbl"]);
+        let fileset = FilesetOptions::new()
+                          .reload_interval_seconds(0)
+                          .open_from_path(&setfile, Box::new(Merger::merge_choose_last_value))
+                          .unwrap();
+        let (started, scanning) = mpsc::channel();
+        let (resume, resumed) = mpsc::channel();
+        let fileset = &fileset;
+        thread::scope(|s| {
+            let scan = s.spawn(move || {
+                let mut it = fileset.iter();
+                let first = it.next();
+                started.send(()).unwrap();
+                resumed.recv().unwrap();
+                (first, it.collect::<Vec<_>>())
+            });
+            scanning.recv().unwrap();
+            // While the scan is open on another thread, a due reload and an
+            // update both take effect right away.
+            write_setfile(&setfile, &["a.mtbl", "b.mtbl"]);
+            assert_eq!(fileset.get("one"), Some("blue".as_bytes().to_vec()));
+            fileset.add_file("c.mtbl").unwrap();
+            assert_eq!(fileset.get("two"), Some("green".as_bytes().to_vec()));
+            assert_eq!(fileset.generation(), 3);
+            resume.send(()).unwrap();
+            let (first, rest) = scan.join().unwrap();
+            assert_eq!(first, Some(("one".as_bytes().to_vec(), "Hello".as_bytes().to_vec())));
+            assert_eq!(rest, vec![("two".as_bytes().to_vec(), "world".as_bytes().to_vec())]);
+        });
+    }
+
+    #[test]
+    fn test_open_dir() {
+        let dir = TempDir::new();
+        create_mtbl(dir.path().join("b.mtbl"), WriterOptions::new(), &[("one", "blue")]);
+        create_mtbl(dir.path().join("a.mtbl"),
+                    WriterOptions::new(),
+                    &[("one", "Hello"), ("two", "world")]);
+        create_mtbl(dir.path().join("c.mtbl.tmp"), WriterOptions::new(), &[("one", "partial")]);
+        create_mtbl(dir.path().join(".d.mtbl"), WriterOptions::new(), &[("one", "partial")]);
+        File::create(dir.path().join("notes.txt")).unwrap();
+        let fileset = FilesetOptions::new()
+                          .reload_interval_seconds(0)
+                          .open_dir(dir.path(), "*.mtbl", Box::new(Merger::merge_choose_last_value))
+                          .unwrap();
+        let names: Vec<_> = fileset.files().into_iter().map(|f| f.path).collect();
+        assert_eq!(names, vec![dir.path().join("a.mtbl"), dir.path().join("b.mtbl")]);
+        assert_eq!(fileset.get("one"), Some("blue".as_bytes().to_vec()));
+
+        fs::rename(dir.path().join("c.mtbl.tmp"), dir.path().join("c.mtbl")).unwrap();
+        assert_eq!(fileset.get("one"), Some("partial".as_bytes().to_vec()));
+        assert_eq!(fileset.files().len(), 3);
+        assert!(fileset.add_file("a.mtbl").is_err());
+
+        // Order by modification time instead: b is the newest.
+        let set_mtime = |name: &str, secs: u64| {
+            let f = File::options().write(true).open(dir.path().join(name)).unwrap();
+            f.set_modified(UNIX_EPOCH + Duration::from_secs(secs)).unwrap();
+        };
+        set_mtime("a.mtbl", 1000);
+        set_mtime("b.mtbl", 3000);
+        set_mtime("c.mtbl", 2000);
+        let by_mtime = FilesetOptions::new()
+                           .order(FileOrder::Modified)
+                           .open_dir(dir.path(), "?.mtbl", Box::new(Merger::merge_choose_last_value))
+                           .unwrap();
+        assert_eq!(by_mtime.get("one"), Some("blue".as_bytes().to_vec()));
+        assert_eq!(by_mtime.get("two"), Some("world".as_bytes().to_vec()));
+
+        let err = Fileset::open_dir(dir.path().join("missing"),
+                                    "*.mtbl",
+                                    Box::new(Merger::merge_choose_last_value))
+                      .err()
+                      .unwrap();
+        assert!(err.to_string().contains("missing"));
+    }
 }
@@ -0,0 +1,487 @@
+//! Parsing of the MTBL file format, for information that libmtbl does not
+//! expose.
+//!
+//! An MTBL file is a sequence of data blocks, followed by an index block and a
+//! fixed-size metadata block. Each block is stored as its length, a CRC32C
+//! checksum and its (possibly compressed) contents. Uncompressed, a block is a
+//! sequence of prefix-compressed entries followed by an array of "restart"
+//! offsets, where entries are stored with their full key. The index block is
+//! never compressed; it maps the last key of each data block to the block's
+//! offset.
+
+#[cfg(feature = "compression")]
+use libc;
+use std::borrow::Cow;
+use std::io::Result as IOResult;
+use std::io::{Error, ErrorKind};
+use std::os::unix::io::RawFd;
+use std::ptr;
+use std::slice;
+
+#[cfg(feature = "compression")]
+use ffi;
+use mtbl_sys::{self, CompressionType};
+
+/// The size of the metadata block at the end of every MTBL file.
+pub const METADATA_SIZE: usize = 512;
+
+// Decompressed data blocks may be at most this many times the file's data
+// block size, or `MIN_MAX_BLOCK_LEN` if that is larger. A block only exceeds
+// the block size by its last entry, so this allows for large values without
+// letting a damaged length claim an arbitrary amount of memory.
+const MAX_BLOCK_EXPANSION: u64 = 64;
+const MIN_MAX_BLOCK_LEN: u64 = 1 << 20;
+const MAX_MAX_BLOCK_LEN: u64 = 1 << 30;
+
+const MAGIC_V1: u32 = 0x7784_6676;
+const MAGIC_V2: u32 = 0x4D54_424C;
+
+/// The version of the file format, which determines how block lengths are
+/// encoded.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum FormatVersion {
+    /// Block lengths are fixed 32-bit integers (mtbl 0.x).
+    V1,
+    /// Block lengths are varints (mtbl 1.x).
+    V2,
+}
+
+/// A read-only memory map of a whole file.
+pub struct Mmap {
+    ptr: *mut libc::c_void,
+    len: usize,
+}
+
+impl Mmap {
+    /// A mapping of nothing.
+    pub fn empty() -> Mmap {
+        Mmap { ptr: ptr::null_mut(), len: 0 }
+    }
+
+    /// Map the file open on `fd`. The mapping stays valid after the file is
+    /// closed.
+    pub fn map(fd: RawFd) -> IOResult<Mmap> {
+        unsafe {
+            let mut stat: libc::stat = std::mem::zeroed();
+            if libc::fstat(fd, &mut stat) != 0 {
+                return Err(Error::last_os_error());
+            }
+            let len = stat.st_size as usize;
+            if len == 0 {
+                return Ok(Mmap::empty());
+            }
+            let ptr = libc::mmap(ptr::null_mut(), len, libc::PROT_READ, libc::MAP_SHARED, fd, 0);
+            if ptr == libc::MAP_FAILED {
+                return Err(Error::last_os_error());
+            }
+            Ok(Mmap { ptr, len })
+        }
+    }
+
+    /// The mapped bytes.
+    pub fn as_slice(&self) -> &[u8] {
+        if self.len == 0 {
+            &[]
+        } else {
+            unsafe { slice::from_raw_parts(self.ptr as *const u8, self.len) }
+        }
+    }
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        if self.len != 0 {
+            unsafe {
+                libc::munmap(self.ptr, self.len);
+            }
+        }
+    }
+}
+
+/// The mapping is read-only, so it can be shared between threads.
+unsafe impl Send for Mmap {}
+
+/// The mapping is read-only, so it can be shared between threads.
+unsafe impl Sync for Mmap {}
+
+/// Decode a little-endian fixed 32-bit integer at `pos`.
+pub fn fixed32(data: &[u8], pos: usize) -> Option<u32> {
+    let bytes = data.get(pos..pos.checked_add(4)?)?;
+    Some(u32::from(bytes[0]) | u32::from(bytes[1]) << 8 | u32::from(bytes[2]) << 16 |
+         u32::from(bytes[3]) << 24)
+}
+
+/// Decode a little-endian fixed 64-bit integer at `pos`.
+pub fn fixed64(data: &[u8], pos: usize) -> Option<u64> {
+    let low = fixed32(data, pos)?;
+    let high = fixed32(data, pos.checked_add(4)?)?;
+    Some(u64::from(low) | u64::from(high) << 32)
+}
+
+/// Decode a varint at the start of `data`, returning its value and length.
+pub fn varint(data: &[u8]) -> Option<(u64, usize)> {
+    let mut value = 0u64;
+    for (i, &b) in data.iter().take(10).enumerate() {
+        value |= u64::from(b & 0x7f) << (7 * i);
+        if b & 0x80 == 0 {
+            return Some((value, i + 1));
+        }
+    }
+    None
+}
+
+/// Determine the format version from the magic number at the end of a file.
+pub fn format_version(data: &[u8]) -> Option<FormatVersion> {
+    if data.len() < METADATA_SIZE {
+        return None;
+    }
+    match fixed32(data, data.len() - 4)? {
+        MAGIC_V1 => Some(FormatVersion::V1),
+        MAGIC_V2 => Some(FormatVersion::V2),
+        _ => None,
+    }
+}
+
+/// Check that `data` is a complete MTBL file whose metadata is sane, whose
+/// index block is intact, and whose data blocks, as listed by the index, lie
+/// before the index. Returns the format version.
+///
+/// The contents of data blocks aren't checked, as that means reading the whole
+/// file; see `Reader::verify`.
+pub fn check_file(data: &[u8]) -> IOResult<FormatVersion> {
+    let invalid = |problem: &str| Error::new(ErrorKind::InvalidData, problem.to_string());
+    let version = format_version(data).ok_or_else(|| {
+        Error::new(ErrorKind::UnexpectedEof,
+                   "no MTBL metadata at the end of the file: file truncated or still being written")
+    })?;
+    // The metadata starts with the index block's offset, the data block size
+    // and the compression algorithm, as fixed 64-bit integers.
+    let metadata = data.len() - METADATA_SIZE;
+    let index_offset = fixed64(data, metadata).ok_or_else(|| invalid("malformed metadata"))?;
+    let compression = fixed64(data, metadata + 16).ok_or_else(|| invalid("malformed metadata"))?;
+    if compression > CompressionType::MTBL_COMPRESSION_LZ4HC as u64 {
+        return Err(invalid("unknown compression algorithm in metadata"));
+    }
+    let index = raw_block(&data[..metadata], index_offset, version)
+        .ok_or_else(|| invalid("index block extends past the metadata"))?;
+    if crc32c(index.contents) != index.crc {
+        return Err(invalid("index block checksum mismatch"));
+    }
+    let block = Block::new(index.contents)
+        .filter(Block::restarts_valid)
+        .ok_or_else(|| invalid("malformed index block"))?;
+    // The data blocks come before the index.
+    let blocks = &data[..index_offset as usize];
+    let mut entries = block.iter();
+    for (_, value) in &mut entries {
+        let (offset, _) = varint(value).ok_or_else(|| invalid("malformed index block"))?;
+        raw_block(blocks, offset, version)
+            .ok_or_else(|| invalid("data block extends past the index block"))?;
+    }
+    if entries.is_corrupt() {
+        return Err(invalid("malformed index block"));
+    }
+    Ok(version)
+}
+
+/// A block as stored in the file.
+pub struct RawBlock<'a> {
+    /// The stored (possibly compressed) contents.
+    pub contents: &'a [u8],
+    /// The CRC32C checksum stored with the contents.
+    pub crc: u32,
+    /// The offset just past the end of the block.
+    pub end: u64,
+}
+
+/// Get the block stored at `offset`, if it lies within `data`.
+pub fn raw_block(data: &[u8], offset: u64, version: FormatVersion) -> Option<RawBlock<'_>> {
+    let offset = offset as usize;
+    let (len, len_size) = match version {
+        FormatVersion::V1 => (u64::from(fixed32(data, offset)?), 4),
+        FormatVersion::V2 => varint(data.get(offset..)?)?,
+    };
+    // The contents follow the length and a CRC32C checksum.
+    let crc = fixed32(data, offset.checked_add(len_size)?)?;
+    let start = offset.checked_add(len_size + 4)?;
+    let end = start.checked_add(len as usize)?;
+    Some(RawBlock {
+        contents: data.get(start..end)?,
+        crc,
+        end: end as u64,
+    })
+}
+
+/// Compute the CRC32C checksum of some data, as stored with each block.
+pub fn crc32c(data: &[u8]) -> u32 {
+    unsafe { mtbl_sys::mtbl_crc32c(data.as_ptr(), data.len()) }
+}
+
+/// The largest size a data block of a file with the given data block size may
+/// decompress to.
+pub fn max_block_len(data_block_size: u64) -> usize {
+    data_block_size.saturating_mul(MAX_BLOCK_EXPANSION)
+                   .clamp(MIN_MAX_BLOCK_LEN, MAX_MAX_BLOCK_LEN) as usize
+}
+
+/// Decompress the stored contents of a data block, which may be at most
+/// `max_len` bytes once decompressed. Contents that are damaged or would be
+/// longer give an `InvalidData` error. Compressed contents need the
+/// `compression` feature; without it they give an `Unsupported` error.
+pub fn decompress(compression: CompressionType,
+                  contents: &[u8],
+                  max_len: usize)
+                  -> IOResult<Cow<'_, [u8]>> {
+    if compression == CompressionType::MTBL_COMPRESSION_NONE {
+        return Ok(Cow::Borrowed(contents));
+    }
+    decompress_owned(compression, contents, max_len).map(Cow::Owned)
+}
+
+#[cfg(feature = "compression")]
+fn decompress_owned(compression: CompressionType,
+                    contents: &[u8],
+                    max_len: usize)
+                    -> IOResult<Vec<u8>> {
+    let decompressed = match compression {
+        CompressionType::MTBL_COMPRESSION_NONE => Some(contents.to_vec()),
+        CompressionType::MTBL_COMPRESSION_SNAPPY => snappy_decompress(contents, max_len),
+        CompressionType::MTBL_COMPRESSION_ZLIB => zlib_decompress(contents, max_len),
+        CompressionType::MTBL_COMPRESSION_LZ4 |
+        CompressionType::MTBL_COMPRESSION_LZ4HC => lz4_decompress(contents, max_len),
+    };
+    decompressed.ok_or_else(|| Error::new(ErrorKind::InvalidData, "failed to decompress block"))
+}
+
+#[cfg(not(feature = "compression"))]
+fn decompress_owned(_compression: CompressionType,
+                    _contents: &[u8],
+                    _max_len: usize)
+                    -> IOResult<Vec<u8>> {
+    Err(Error::new(ErrorKind::Unsupported,
+                   "decompressing blocks needs the `compression` feature"))
+}
+
+#[cfg(feature = "compression")]
+fn snappy_decompress(contents: &[u8], max_len: usize) -> Option<Vec<u8>> {
+    let input = contents.as_ptr() as *const libc::c_char;
+    unsafe {
+        let mut len = 0;
+        if ffi::snappy_uncompressed_length(input, contents.len(), &mut len) != ffi::SNAPPY_OK {
+            return None;
+        }
+        if len > max_len {
+            return None;
+        }
+        let mut output = vec![0u8; len];
+        let res = ffi::snappy_uncompress(input,
+                                         contents.len(),
+                                         output.as_mut_ptr() as *mut libc::c_char,
+                                         &mut len);
+        if res != ffi::SNAPPY_OK {
+            return None;
+        }
+        output.truncate(len);
+        Some(output)
+    }
+}
+
+#[cfg(feature = "compression")]
+fn zlib_decompress(contents: &[u8], max_len: usize) -> Option<Vec<u8>> {
+    // zlib streams don't record their decompressed size, so grow the output
+    // until it fits, up to `max_len`.
+    let mut capacity = contents.len().saturating_mul(4).max(1 << 16).min(max_len);
+    loop {
+        let mut output = vec![0u8; capacity];
+        let mut len = capacity as libc::c_ulong;
+        let res = unsafe {
+            ffi::uncompress(output.as_mut_ptr(),
+                            &mut len,
+                            contents.as_ptr(),
+                            contents.len() as libc::c_ulong)
+        };
+        match res {
+            ffi::Z_OK => {
+                output.truncate(len as usize);
+                return Some(output);
+            }
+            ffi::Z_BUF_ERROR if capacity < max_len => {
+                capacity = capacity.saturating_mul(2).min(max_len)
+            }
+            _ => return None,
+        }
+    }
+}
+
+#[cfg(feature = "compression")]
+fn lz4_decompress(contents: &[u8], max_len: usize) -> Option<Vec<u8>> {
+    // libmtbl stores the decompressed size before the LZ4 block.
+    let len = fixed32(contents, 0)? as usize;
+    let input = &contents[4..];
+    if len > max_len || len > i32::MAX as usize || input.len() > i32::MAX as usize {
+        return None;
+    }
+    let mut output = vec![0u8; len];
+    let res = unsafe {
+        ffi::LZ4_decompress_safe(input.as_ptr() as *const libc::c_char,
+                                 output.as_mut_ptr() as *mut libc::c_char,
+                                 input.len() as libc::c_int,
+                                 len as libc::c_int)
+    };
+    if res < 0 || res as usize != len {
+        return None;
+    }
+    Some(output)
+}
+
+/// The entries of an uncompressed block.
+pub struct Block<'a> {
+    entries: &'a [u8],
+    // The offsets of the entries stored with their full key, as fixed 32-bit
+    // integers.
+    restarts: &'a [u8],
+}
+
+impl<'a> Block<'a> {
+    /// Parse the restart array at the end of an uncompressed block.
+    pub fn new(data: &'a [u8]) -> Option<Block<'a>> {
+        let num_restarts = fixed32(data, data.len().checked_sub(4)?)? as usize;
+        let restarts_len = num_restarts.checked_mul(4)?;
+        let entries_len = data.len().checked_sub(4)?.checked_sub(restarts_len)?;
+        Some(Block {
+            entries: &data[..entries_len],
+            restarts: &data[entries_len..entries_len + restarts_len],
+        })
+    }
+
+    /// Iterate over the entries, starting from the beginning of the block.
+    pub fn iter(&self) -> BlockIter<'a> {
+        self.iter_from(0)
+    }
+
+    /// Iterate over the entries, starting far enough back that at least the
+    /// two entries before the first key at or after `target` are included.
+    ///
+    /// This binary searches the restart points, so only a few entries are
+    /// decoded before reaching `target`.
+    pub fn iter_before(&self, target: &[u8]) -> BlockIter<'a> {
+        let (mut lo, mut hi) = (0, self.num_restarts());
+        // Find the number of restart points whose keys are before `target`.
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            match self.restart_key(mid) {
+                Some(key) if key < target => lo = mid + 1,
+                Some(_) => hi = mid,
+                None => return self.iter(),
+            }
+        }
+        // The restart point before the last one that is before `target` has
+        // at least one entry before `target` after it, and the restart point
+        // before that a whole interval more.
+        let restart = lo.saturating_sub(2);
+        match fixed32(self.restarts, restart * 4) {
+            Some(offset) => self.iter_from(offset as usize),
+            None => self.iter(),
+        }
+    }
+
+    /// The number of restart points.
+    pub fn num_restarts(&self) -> usize {
+        self.restarts.len() / 4
+    }
+
+    /// Whether each restart point is after the previous one and is the offset
+    /// of an entry stored with its full key.
+    pub fn restarts_valid(&self) -> bool {
+        let mut previous = None;
+        for restart in 0..self.num_restarts() {
+            let offset = fixed32(self.restarts, restart * 4);
+            if offset <= previous || self.restart_key(restart).is_none() {
+                return false;
+            }
+            previous = offset;
+        }
+        true
+    }
+
+    fn iter_from(&self, pos: usize) -> BlockIter<'a> {
+        BlockIter {
+            data: self.entries,
+            pos,
+            key: Vec::new(),
+            corrupt: false,
+        }
+    }
+
+    /// The key of the entry at a restart point.
+    fn restart_key(&self, restart: usize) -> Option<&'a [u8]> {
+        let mut pos = fixed32(self.restarts, restart * 4)? as usize;
+        let mut header = [0usize; 2];
+        for field in &mut header {
+            let (value, len) = varint(self.entries.get(pos..)?)?;
+            *field = value as usize;
+            pos += len;
+        }
+        let [shared, non_shared] = header;
+        // Skip the value length.
+        pos += varint(self.entries.get(pos..)?)?.1;
+        if shared != 0 {
+            return None;
+        }
+        self.entries.get(pos..pos.checked_add(non_shared)?)
+    }
+}
+
+/// An iterator over the entries of a block, yielding each key and value.
+///
+/// Iteration stops early if the block is malformed.
+pub struct BlockIter<'a> {
+    data: &'a [u8],
+    pos: usize,
+    key: Vec<u8>,
+    corrupt: bool,
+}
+
+impl<'a> BlockIter<'a> {
+    /// Whether iteration stopped early because the block is malformed.
+    pub fn is_corrupt(&self) -> bool {
+        self.corrupt
+    }
+
+    fn parse_next(&mut self) -> Option<(Vec<u8>, &'a [u8])> {
+        let mut pos = self.pos;
+        let mut header = [0usize; 3];
+        for field in &mut header {
+            let (value, len) = varint(self.data.get(pos..)?)?;
+            *field = value as usize;
+            pos += len;
+        }
+        let [shared, non_shared, value_len] = header;
+        if shared > self.key.len() {
+            return None;
+        }
+        let key_end = pos.checked_add(non_shared)?;
+        let value_end = key_end.checked_add(value_len)?;
+        let key_delta = self.data.get(pos..key_end)?;
+        let value = self.data.get(key_end..value_end)?;
+        self.key.truncate(shared);
+        self.key.extend_from_slice(key_delta);
+        self.pos = value_end;
+        Some((self.key.clone(), value))
+    }
+}
+
+impl<'a> Iterator for BlockIter<'a> {
+    type Item = (Vec<u8>, &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.corrupt || self.pos >= self.data.len() {
+            return None;
+        }
+        let entry = self.parse_next();
+        if entry.is_none() {
+            self.corrupt = true;
+        }
+        entry
+    }
+}
@@ -0,0 +1,245 @@
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+
+use reader::{Iter, Read, Reader};
+
+/// All the values found for one key, each tagged with the index of the source
+/// it came from. Values are listed in source order.
+pub type GroupedValues = Vec<(usize, Vec<u8>)>;
+
+/// An MTBL reader that reads from several sources at once without collapsing
+/// the values of colliding keys.
+///
+/// Where a [`Merger`](struct.Merger.html) combines colliding values with a
+/// [`MergeFn`](type.MergeFn.html) as it goes, a `GroupedMerger` hands back
+/// every value for a key along with the index of the source that provided it.
+/// That makes it easy to apply provenance-aware policies ("newest file wins",
+/// "flag conflicts") in plain Rust.
+///
+/// Like a [`Merger`](struct.Merger.html), a `GroupedMerger` owns its sources,
+/// which by default are `Reader`s; use boxed trait objects to mix different
+/// kinds of sources.
+///
+/// # Examples
+///
+/// ```
+/// let merger = GroupedMerger::new(vec![old_reader, new_reader]);
+/// for (key, values) in &merger {
+///     // The last value comes from the newest source.
+///     let &(source, ref value) = values.last().unwrap();
+/// }
+/// ```
+pub struct GroupedMerger<S: Read = Reader> {
+    sources: Vec<S>,
+}
+
+impl<S: Read> GroupedMerger<S> {
+    /// Create a grouped merger from a collection of sources. Each source is
+    /// identified by its index in `sources`.
+    pub fn new(sources: Vec<S>) -> GroupedMerger<S> {
+        GroupedMerger { sources }
+    }
+
+    /// Add an additional source of data. Its index is the number of sources
+    /// added before it.
+    pub fn add_source(&mut self, source: S) {
+        self.sources.push(source);
+    }
+
+    /// The sources being merged, in source index order.
+    pub fn sources(&self) -> &[S] {
+        &self.sources
+    }
+
+    /// The number of sources being merged.
+    pub fn len(&self) -> usize {
+        self.sources.len()
+    }
+
+    /// Whether there are no sources being merged.
+    pub fn is_empty(&self) -> bool {
+        self.sources.is_empty()
+    }
+
+    /// Get all values for a key, tagged with their source indexes, if any
+    /// source contains it.
+    pub fn get<T: AsRef<[u8]>>(&self, key: T) -> Option<GroupedValues> {
+        let key = key.as_ref();
+        let values: GroupedValues = self.sources
+                                        .iter()
+                                        .enumerate()
+                                        .filter_map(|(i, s)| s.get(key).map(|v| (i, v)))
+                                        .collect();
+        if values.is_empty() {
+            None
+        } else {
+            Some(values)
+        }
+    }
+
+    /// Get an iterator over all keys and their grouped values.
+    pub fn iter(&self) -> GroupedIter<'_> {
+        GroupedIter::new(self.sources.iter().map(|s| s.iter()).collect())
+    }
+
+    /// Get an iterator over all keys and their grouped values where the key
+    /// starts with the given prefix.
+    pub fn get_prefix<T: AsRef<[u8]>>(&self, prefix: T) -> GroupedIter<'_> {
+        let prefix = prefix.as_ref();
+        GroupedIter::new(self.sources.iter().map(|s| s.get_prefix(prefix)).collect())
+    }
+
+    /// Get an iterator over all keys and their grouped values, where the keys
+    /// are between key0 and key1 (inclusive).
+    pub fn get_range<T, U>(&self, key0: T, key1: U) -> GroupedIter<'_>
+        where T: AsRef<[u8]>,
+              U: AsRef<[u8]>
+    {
+        let key0 = key0.as_ref();
+        let key1 = key1.as_ref();
+        GroupedIter::new(self.sources.iter().map(|s| s.get_range(key0, key1)).collect())
+    }
+}
+
+impl<'a, S: Read> IntoIterator for &'a GroupedMerger<S> {
+    type Item = (Vec<u8>, GroupedValues);
+    type IntoIter = GroupedIter<'a>;
+
+    fn into_iter(self) -> GroupedIter<'a> {
+        self.iter()
+    }
+}
+
+/// The next pending entry from one source, ordered so that the smallest key
+/// (and, among equal keys, the lowest source index) is at the top of a
+/// max-heap.
+struct HeapEntry {
+    key: Vec<u8>,
+    source: usize,
+    value: Vec<u8>,
+}
+
+impl Ord for HeapEntry {
+    fn cmp(&self, other: &HeapEntry) -> Ordering {
+        other.key.cmp(&self.key).then_with(|| other.source.cmp(&self.source))
+    }
+}
+
+impl PartialOrd for HeapEntry {
+    fn partial_cmp(&self, other: &HeapEntry) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for HeapEntry {
+    fn eq(&self, other: &HeapEntry) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for HeapEntry {}
+
+/// An iterator over the entries of a [`GroupedMerger`](struct.GroupedMerger.html),
+/// yielding each key once with all of its values in source order.
+pub struct GroupedIter<'a> {
+    iters: Vec<Iter<'a>>,
+    heap: BinaryHeap<HeapEntry>,
+}
+
+impl<'a> GroupedIter<'a> {
+    pub(crate) fn new(iters: Vec<Iter<'a>>) -> GroupedIter<'a> {
+        let mut grouped = GroupedIter {
+            iters,
+            heap: BinaryHeap::new(),
+        };
+        for source in 0..grouped.iters.len() {
+            grouped.advance(source);
+        }
+        grouped
+    }
+
+    /// Pull the next entry from the given source onto the heap.
+    fn advance(&mut self, source: usize) {
+        if let Some((key, value)) = self.iters[source].next() {
+            self.heap.push(HeapEntry { key, source, value });
+        }
+    }
+}
+
+impl<'a> Iterator for GroupedIter<'a> {
+    /// A key and all of its (source index, value) pairs.
+    type Item = (Vec<u8>, GroupedValues);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let first = self.heap.pop()?;
+        self.advance(first.source);
+        let key = first.key;
+        let mut values = vec![(first.source, first.value)];
+        while self.heap.peek().is_some_and(|e| e.key == key) {
+            let entry = self.heap.pop().unwrap();
+            self.advance(entry.source);
+            values.push((entry.source, entry.value));
+        }
+        Some((key, values))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use grouped_merger::GroupedMerger;
+    use reader;
+    use test_util::{create_reader, v};
+    use test_util;
+
+    /// Set up readers with collisions for "one" and "two".
+    fn set_up_readers() -> Vec<reader::Reader> {
+        let mut readers = test_util::set_up_readers();
+        readers.push(create_reader(&[("one", "red"), ("two", "yellow")]));
+        readers
+    }
+
+    #[test]
+    fn test_grouped_get() {
+        let merger = GroupedMerger::new(set_up_readers());
+        assert_eq!(merger.len(), 3);
+        assert_eq!(merger.get("a"), None);
+        assert_eq!(merger.get("one"),
+                   Some(vec![(0, v("Hello")), (1, v("blue")), (2, v("red"))]));
+        assert_eq!(merger.get("two"), Some(vec![(0, v("world")), (2, v("yellow"))]));
+        assert_eq!(merger.get("three"), Some(vec![(1, v("green"))]));
+    }
+
+    #[test]
+    fn test_grouped_iter() {
+        let merger = GroupedMerger::new(set_up_readers());
+        let mut it = merger.iter();
+        assert_eq!(it.next(),
+                   Some((v("one"), vec![(0, v("Hello")), (1, v("blue")), (2, v("red"))])));
+        assert_eq!(it.next(), Some((v("three"), vec![(1, v("green"))])));
+        assert_eq!(it.next(), Some((v("two"), vec![(0, v("world")), (2, v("yellow"))])));
+        assert_eq!(it.next(), None);
+        assert_eq!((&merger).into_iter().count(), 3);
+    }
+
+    #[test]
+    fn test_grouped_prefix_and_range() {
+        let merger = GroupedMerger::new(set_up_readers());
+        let mut it = merger.get_prefix("t");
+        assert_eq!(it.next(), Some((v("three"), vec![(1, v("green"))])));
+        assert_eq!(it.next(), Some((v("two"), vec![(0, v("world")), (2, v("yellow"))])));
+        assert_eq!(it.next(), None);
+        let mut it = merger.get_range("o", "three");
+        assert_eq!(it.next().map(|(k, vs)| (k, vs.len())), Some((v("one"), 3)));
+        assert_eq!(it.next().map(|(k, vs)| (k, vs.len())), Some((v("three"), 1)));
+        assert_eq!(it.next(), None);
+    }
+
+    #[test]
+    fn test_newest_wins_policy() {
+        let merger = GroupedMerger::new(set_up_readers());
+        let newest: Vec<(Vec<u8>, usize)> = merger.iter()
+                                                  .map(|(k, mut vs)| (k, vs.pop().unwrap().0))
+                                                  .collect();
+        assert_eq!(newest, vec![(v("one"), 2), (v("three"), 1), (v("two"), 2)]);
+    }
+}
@@ -0,0 +1,505 @@
+use std::fmt;
+use std::io::Result as IOResult;
+use std::io::{Error, ErrorKind};
+
+use format::{self, Block, BlockIter, FormatVersion, Mmap};
+use mtbl_sys::CompressionType;
+use reader::Reader;
+
+/// A description of one data block of an MTBL file, from
+/// [`Reader::blocks`](struct.Reader.html#method.blocks).
+#[derive(Clone,Debug,PartialEq)]
+pub struct BlockInfo {
+    /// The byte offset of the block in the file.
+    pub offset: u64,
+    /// The size of the block's contents as stored, in bytes.
+    pub compressed_size: u64,
+    /// The size of the block's contents once decompressed, in bytes.
+    pub uncompressed_size: u64,
+    /// The number of entries in the block.
+    pub count_entries: u64,
+    /// The number of restart points, where entries are stored with their full
+    /// key.
+    pub count_restarts: u64,
+    /// The first key in the block.
+    pub first_key: Vec<u8>,
+    /// The last key in the block.
+    pub last_key: Vec<u8>,
+    /// The block's key in the index, which is at least its last key and less
+    /// than the first key of the next block.
+    pub index_key: Vec<u8>,
+    /// The CRC32C checksum stored with the block's contents.
+    pub crc: u32,
+}
+
+/// An entry of the index block, which maps a key at or after the end of a data
+/// block to the block's offset.
+#[derive(Clone,Debug,PartialEq)]
+pub struct IndexEntry {
+    /// A key at least the last key of the data block, and less than the first
+    /// key of the next one.
+    pub key: Vec<u8>,
+    /// The byte offset of the data block in the file.
+    pub offset: u64,
+}
+
+/// An iterator over the entries of an index block, from
+/// [`Reader::index_entries`](struct.Reader.html#method.index_entries).
+///
+/// Iteration stops early if the index is malformed.
+pub struct IndexEntries<'a> {
+    entries: BlockIter<'a>,
+}
+
+impl<'a> IndexEntries<'a> {
+    pub(crate) fn new(entries: BlockIter<'a>) -> IndexEntries<'a> {
+        IndexEntries { entries }
+    }
+}
+
+impl<'a> Iterator for IndexEntries<'a> {
+    type Item = IndexEntry;
+
+    fn next(&mut self) -> Option<IndexEntry> {
+        let (key, value) = self.entries.next()?;
+        // The value is the block's offset as a varint.
+        let (offset, _) = format::varint(value)?;
+        Some(IndexEntry { key, offset })
+    }
+}
+
+/// An iterator describing each data block of an MTBL file in order, from
+/// [`Reader::blocks`](struct.Reader.html#method.blocks).
+///
+/// Each block is decompressed and its entries are decoded, so this reads the
+/// whole file. A block that can't be read gives an error, and iteration goes
+/// on with the next one.
+pub struct Blocks<'a> {
+    data: Mmap,
+    version: FormatVersion,
+    compression: CompressionType,
+    max_block_len: usize,
+    index: IndexEntries<'a>,
+}
+
+impl<'a> Blocks<'a> {
+    pub(crate) fn new(data: Mmap,
+                      version: FormatVersion,
+                      compression: CompressionType,
+                      max_block_len: usize,
+                      index: IndexEntries<'a>)
+                      -> Blocks<'a> {
+        Blocks {
+            data,
+            version,
+            compression,
+            max_block_len,
+            index,
+        }
+    }
+
+    fn describe(&self, entry: IndexEntry) -> IOResult<BlockInfo> {
+        let invalid = |problem: &str| {
+            Error::new(ErrorKind::InvalidData,
+                       format!("data block at offset {} {}", entry.offset, problem))
+        };
+        let raw = format::raw_block(self.data.as_slice(), entry.offset, self.version)
+            .ok_or_else(|| invalid("extends past the end of the file"))?;
+        let contents = format::decompress(self.compression, raw.contents, self.max_block_len)
+            .map_err(|e| {
+                match e.kind() {
+                    ErrorKind::Unsupported => e,
+                    _ => invalid("could not be decompressed"),
+                }
+            })?;
+        let block = Block::new(&contents).ok_or_else(|| invalid("has a malformed restart array"))?;
+        let mut count_entries = 0;
+        let mut keys = (None, None);
+        for (key, _) in block.iter() {
+            count_entries += 1;
+            if keys.0.is_none() {
+                keys.0 = Some(key.clone());
+            }
+            keys.1 = Some(key);
+        }
+        let (first_key, last_key) = match keys {
+            (Some(first), Some(last)) => (first, last),
+            _ => return Err(invalid("has no entries")),
+        };
+        Ok(BlockInfo {
+            offset: entry.offset,
+            compressed_size: raw.contents.len() as u64,
+            uncompressed_size: contents.len() as u64,
+            count_entries,
+            count_restarts: block.num_restarts() as u64,
+            first_key,
+            last_key,
+            index_key: entry.key,
+            crc: raw.crc,
+        })
+    }
+}
+
+impl<'a> Iterator for Blocks<'a> {
+    type Item = IOResult<BlockInfo>;
+
+    fn next(&mut self) -> Option<IOResult<BlockInfo>> {
+        let entry = self.index.next()?;
+        Some(self.describe(entry))
+    }
+}
+
+/// The kinds of problem that [`Reader::verify`](struct.Reader.html#method.verify)
+/// can find.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum ProblemKind {
+    /// A block extends past the end of the file, or the blocks don't follow
+    /// one another.
+    Layout,
+    /// A block's contents don't match its stored checksum.
+    Checksum,
+    /// A block can't be decompressed or its entries can't be decoded.
+    Corrupt,
+    /// Keys are out of order within a block, across blocks or in the index.
+    KeyOrder,
+    /// A total in the metadata block doesn't match the file's contents.
+    Metadata,
+    /// The file couldn't be read at all, or a block couldn't be decompressed
+    /// because the `compression` feature isn't enabled.
+    Unreadable,
+}
+
+/// A problem found by [`Reader::verify`](struct.Reader.html#method.verify).
+#[derive(Clone,Debug,PartialEq)]
+pub struct Problem {
+    /// The offset of the block with the problem. Problems with the metadata
+    /// block give the index block's offset.
+    pub offset: u64,
+    /// What sort of problem it is.
+    pub kind: ProblemKind,
+    /// A description of the problem.
+    pub detail: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?} problem at offset {}: {}", self.kind, self.offset, self.detail)
+    }
+}
+
+/// The result of [`Reader::verify`](struct.Reader.html#method.verify).
+#[derive(Clone,Debug,Default,PartialEq)]
+pub struct VerifyReport {
+    /// The number of data blocks checked.
+    pub blocks: u64,
+    /// The number of entries decoded from the data blocks.
+    pub entries: u64,
+    /// Every problem found, in the order the blocks appear in the index.
+    pub problems: Vec<Problem>,
+}
+
+impl VerifyReport {
+    /// Whether no problems were found.
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    fn problem(&mut self, offset: u64, kind: ProblemKind, detail: String) {
+        self.problems.push(Problem { offset, kind, detail });
+    }
+}
+
+/// Check every data block and the index of an MTBL file; see
+/// [`Reader::verify`](struct.Reader.html#method.verify).
+pub(crate) fn verify(reader: &Reader) -> VerifyReport {
+    let data = match reader.map() {
+        Ok(data) => data,
+        Err(e) => {
+            let mut report = VerifyReport::default();
+            report.problem(0, ProblemKind::Unreadable, format!("could not map the file: {}", e));
+            return report;
+        }
+    };
+    let mut verifier = Verifier {
+        data: data.as_slice(),
+        version: reader.format_version(),
+        compression: reader.compression_algorithm(),
+        max_block_len: format::max_block_len(reader.data_block_size()),
+        report: VerifyReport::default(),
+        bytes_data_blocks: 0,
+        bytes_keys: 0,
+        bytes_values: 0,
+    };
+    let index_offset = reader.index_block_offset();
+    let bytes_index_block = verifier.verify_index(index_offset);
+    let mut report = verifier.report;
+    let metadata = [("count_entries", reader.count_entries(), report.entries),
+                    ("count_data_blocks", reader.count_data_blocks(), report.blocks),
+                    ("bytes_data_blocks", reader.bytes_data_blocks(), verifier.bytes_data_blocks),
+                    ("bytes_index_block", reader.bytes_index_block(), bytes_index_block),
+                    ("bytes_keys", reader.bytes_keys(), verifier.bytes_keys),
+                    ("bytes_values", reader.bytes_values(), verifier.bytes_values)];
+    for &(name, expected, found) in &metadata {
+        if expected != found {
+            report.problem(index_offset,
+                           ProblemKind::Metadata,
+                           format!("metadata gives {} as {}, but found {}", name, expected, found));
+        }
+    }
+    report
+}
+
+/// The state of a [`verify`](fn.verify.html) run: where the file is, what has
+/// been found, and totals over the data blocks to compare with the metadata.
+struct Verifier<'a> {
+    data: &'a [u8],
+    version: FormatVersion,
+    compression: CompressionType,
+    max_block_len: usize,
+    report: VerifyReport,
+    bytes_data_blocks: u64,
+    bytes_keys: u64,
+    bytes_values: u64,
+}
+
+impl<'a> Verifier<'a> {
+    fn problem(&mut self, offset: u64, kind: ProblemKind, detail: &str) {
+        self.report.problem(offset, kind, detail.to_string());
+    }
+
+    /// Check the index block at `offset` and the data blocks it lists,
+    /// returning the index block's size.
+    fn verify_index(&mut self, offset: u64) -> u64 {
+        let index = match format::raw_block(self.data, offset, self.version) {
+            Some(index) => index,
+            None => {
+                self.problem(offset,
+                             ProblemKind::Layout,
+                             "index block extends past the end of the file");
+                return 0;
+            }
+        };
+        if format::crc32c(index.contents) != index.crc {
+            self.problem(offset, ProblemKind::Checksum, "index block checksum mismatch");
+        }
+        let block = match Block::new(index.contents) {
+            Some(block) => block,
+            None => {
+                self.problem(offset,
+                             ProblemKind::Corrupt,
+                             "index block has a malformed restart array");
+                return index.end - offset;
+            }
+        };
+        if !block.restarts_valid() {
+            self.problem(offset, ProblemKind::Corrupt, "index block has an invalid restart point");
+        }
+        // The end of the previous data block, and its index key.
+        let mut expected_offset = 0;
+        let mut previous_key: Option<Vec<u8>> = None;
+        let mut entries = block.iter();
+        for (index_key, value) in &mut entries {
+            let block_offset = match format::varint(value) {
+                Some((block_offset, _)) => block_offset,
+                None => {
+                    self.problem(offset,
+                                 ProblemKind::Corrupt,
+                                 "index entry has a malformed offset");
+                    break;
+                }
+            };
+            if previous_key.as_ref().is_some_and(|previous| *previous >= index_key) {
+                self.report.problem(offset,
+                                    ProblemKind::KeyOrder,
+                                    format!("index key for the block at offset {} is out of order",
+                                            block_offset));
+            }
+            if block_offset != expected_offset {
+                self.report.problem(block_offset,
+                                    ProblemKind::Layout,
+                                    format!("block should be at offset {}", expected_offset));
+            }
+            self.report.blocks += 1;
+            let end = self.verify_block(block_offset, previous_key.as_deref(), &index_key);
+            if let Some(end) = end {
+                expected_offset = end;
+            }
+            previous_key = Some(index_key);
+        }
+        if entries.is_corrupt() {
+            self.problem(offset, ProblemKind::Corrupt, "index block has a malformed entry");
+        }
+        if expected_offset != offset {
+            self.report.problem(offset,
+                                ProblemKind::Layout,
+                                format!("index block should be at offset {}", expected_offset));
+        }
+        index.end - offset
+    }
+
+    /// Check the data block at `offset`, whose keys should be after
+    /// `previous_key` and at most `index_key`, returning the offset of its end
+    /// if it lies within the file.
+    fn verify_block(&mut self,
+                    offset: u64,
+                    previous_key: Option<&[u8]>,
+                    index_key: &[u8])
+                    -> Option<u64> {
+        let raw = match format::raw_block(self.data, offset, self.version) {
+            Some(raw) => raw,
+            None => {
+                self.problem(offset,
+                             ProblemKind::Layout,
+                             "data block extends past the end of the file");
+                return None;
+            }
+        };
+        self.bytes_data_blocks += raw.end - offset;
+        if format::crc32c(raw.contents) != raw.crc {
+            self.problem(offset, ProblemKind::Checksum, "data block checksum mismatch");
+        }
+        let max_len = self.max_block_len;
+        let contents = match format::decompress(self.compression, raw.contents, max_len) {
+            Ok(contents) => contents,
+            Err(ref e) if e.kind() == ErrorKind::Unsupported => {
+                self.problem(offset,
+                             ProblemKind::Unreadable,
+                             "data block is compressed, which needs the `compression` feature");
+                return Some(raw.end);
+            }
+            Err(_) => {
+                self.problem(offset, ProblemKind::Corrupt, "data block could not be decompressed");
+                return Some(raw.end);
+            }
+        };
+        let block = match Block::new(&contents) {
+            Some(block) => block,
+            None => {
+                self.problem(offset,
+                             ProblemKind::Corrupt,
+                             "data block has a malformed restart array");
+                return Some(raw.end);
+            }
+        };
+        if !block.restarts_valid() {
+            self.problem(offset, ProblemKind::Corrupt, "data block has an invalid restart point");
+        }
+        let mut entries = block.iter();
+        let mut last_key: Option<Vec<u8>> = None;
+        for (key, value) in &mut entries {
+            self.report.entries += 1;
+            self.bytes_keys += key.len() as u64;
+            self.bytes_values += value.len() as u64;
+            let in_order = match last_key {
+                Some(ref last) => *last < key,
+                None => previous_key.is_none_or(|previous| previous < &key[..]),
+            };
+            if !in_order {
+                self.report.problem(offset,
+                                    ProblemKind::KeyOrder,
+                                    format!("key {:?} is out of order",
+                                            String::from_utf8_lossy(&key)));
+            }
+            last_key = Some(key);
+        }
+        if entries.is_corrupt() {
+            self.problem(offset, ProblemKind::Corrupt, "data block has a malformed entry");
+        }
+        match last_key {
+            Some(ref last) if &last[..] > index_key => {
+                self.problem(offset,
+                             ProblemKind::KeyOrder,
+                             "data block's last key is after its index key");
+            }
+            Some(_) => {}
+            None => self.problem(offset, ProblemKind::Corrupt, "data block has no entries"),
+        }
+        Some(raw.end)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempfile;
+    use self::tempfile::NamedTempFile;
+
+    use std::fs;
+    use std::io::ErrorKind;
+    use std::sync::Arc;
+
+    use inspect::ProblemKind;
+    use reader::{CompressionType, Reader};
+    use test_util::{create_mtbl, numbered};
+    use writer::WriterOptions;
+
+    #[test]
+    fn test_blocks() {
+        for &compression in &[CompressionType::MTBL_COMPRESSION_NONE,
+                              CompressionType::MTBL_COMPRESSION_ZLIB] {
+            let tempfile_writer = NamedTempFile::new().unwrap();
+            let tempfile_reader = tempfile_writer.reopen().unwrap();
+            create_mtbl(tempfile_writer.path(),
+                        WriterOptions::new().block_size(64).compression(compression),
+                        &numbered(1000));
+            let reader = Reader::open_from_file(&tempfile_reader).unwrap();
+            if compression != CompressionType::MTBL_COMPRESSION_NONE &&
+               !cfg!(feature = "compression") {
+                // Compressed blocks can't be decoded without the feature.
+                assert!(reader.blocks()
+                              .unwrap()
+                              .all(|b| b.unwrap_err().kind() == ErrorKind::Unsupported));
+                continue;
+            }
+            let blocks: Vec<_> = reader.blocks().unwrap().map(Result::unwrap).collect();
+            assert_eq!(blocks.len() as u64, reader.count_data_blocks());
+            assert_eq!(blocks.iter().map(|b| b.count_entries).sum::<u64>(), 1000);
+            assert_eq!(blocks[0].offset, 0);
+            assert_eq!(blocks[0].first_key, b"key0000");
+            assert_eq!(blocks[blocks.len() - 1].last_key, b"key0999");
+            for (block, next) in blocks.iter().zip(&blocks[1..]) {
+                assert!(block.offset + block.compressed_size < next.offset);
+                assert!(block.last_key <= block.index_key && block.index_key < next.first_key);
+            }
+            for block in &blocks {
+                assert!(block.first_key <= block.last_key);
+                assert!(block.count_restarts >= 1 && block.count_restarts <= block.count_entries);
+                if compression == CompressionType::MTBL_COMPRESSION_NONE {
+                    assert_eq!(block.compressed_size, block.uncompressed_size);
+                }
+            }
+            let index: Vec<_> = reader.index_entries().unwrap().collect();
+            assert_eq!(index.iter().map(|e| e.offset).collect::<Vec<_>>(),
+                       blocks.iter().map(|b| b.offset).collect::<Vec<_>>());
+            assert_eq!(index.iter().map(|e| &e.key).collect::<Vec<_>>(),
+                       blocks.iter().map(|b| &b.index_key).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn test_verify() {
+        let tempfile_writer = NamedTempFile::new().unwrap();
+        let path = tempfile_writer.path().to_path_buf();
+        create_mtbl(&path, WriterOptions::new().block_size(64), &numbered(1000));
+        let reader = Arc::new(Reader::open_from_path(&path).unwrap());
+        let report = reader.verify();
+        assert!(report.is_ok(), "{:?}", report.problems);
+        assert_eq!(report.blocks, reader.count_data_blocks());
+        assert_eq!(report.entries, 1000);
+        assert_eq!(reader.clone().verify_in_background().join().unwrap(), report);
+
+        // Damage the third data block.
+        let block = reader.blocks().unwrap().nth(2).unwrap().unwrap();
+        let mut data = fs::read(&path).unwrap();
+        // The contents follow a one-byte length and the checksum.
+        data[(block.offset + 5 + block.compressed_size / 2) as usize] ^= 0xff;
+        let damaged = NamedTempFile::new().unwrap();
+        fs::write(damaged.path(), &data).unwrap();
+        let report = Reader::open_from_path(damaged.path()).unwrap().verify();
+        assert!(!report.is_ok());
+        let problem = report.problems.iter().find(|p| p.kind == ProblemKind::Checksum).unwrap();
+        assert_eq!(problem.offset, block.offset);
+        assert_eq!(problem.to_string(),
+                   format!("Checksum problem at offset {}: data block checksum mismatch",
+                           block.offset));
+    }
+}
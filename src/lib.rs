@@ -41,6 +41,19 @@
 //! }
 //! ```
 //!
+//! With the `rayon` feature enabled, `Reader::par_iter` and
+//! `Reader::par_range` scan a file on several threads at once.
+//!
+//! `Reader::blocks` and `Reader::verify` decode data blocks themselves. To
+//! decode compressed blocks they need the `compression` feature, which links
+//! against the zlib, snappy and lz4 libraries that libmtbl is built with.
+//!
+//! ## Updating a database
+//!
+//! MTBL files can't be changed once written. With the `store` feature enabled,
+//! the [`store`](store/index.html) module layers them into a key-value store
+//! that supports puts, deletes and scans.
+//!
 //! # More details about MTBL
 //!
 //! Quoting from the MTBL documentation:
@@ -100,23 +113,74 @@
 
 extern crate libc;
 extern crate mtbl_sys;
+#[cfg(feature = "rayon")]
+extern crate rayon;
 
+mod compactor;
+#[cfg(feature = "compression")]
+mod ffi;
 mod fileset;
+mod format;
+mod grouped_merger;
+mod inspect;
+mod memtable;
+mod merge_planner;
 mod merger;
 mod reader;
+mod reader_pool;
 mod sorter;
+mod source;
+mod swappable;
+#[cfg(test)]
+mod test_util;
+mod watcher;
 mod writer;
 
+#[cfg(feature = "store")]
+pub mod store;
+pub mod tombstone;
+
+pub use compactor::Compactor;
+pub use compactor::CompactorOptions;
+pub use compactor::CompactorThread;
+pub use fileset::FileInfo;
+pub use fileset::FileOrder;
 pub use fileset::Fileset;
 pub use fileset::FilesetOptions;
+pub use fileset::Snapshot;
+pub use grouped_merger::GroupedIter;
+pub use grouped_merger::GroupedMerger;
+pub use grouped_merger::GroupedValues;
+pub use inspect::BlockInfo;
+pub use inspect::Blocks;
+pub use inspect::IndexEntries;
+pub use inspect::IndexEntry;
+pub use inspect::Problem;
+pub use inspect::ProblemKind;
+pub use inspect::VerifyReport;
+pub use memtable::MemTable;
+pub use merge_planner::MergePlanner;
+pub use merge_planner::MergePlannerOptions;
 pub use merger::MergeFn;
 pub use merger::Merger;
+pub use merger::SyncMergeFn;
 pub use reader::Iter;
+pub use reader::KeyBounds;
+pub use reader::Nearest;
+pub use reader::Query;
 pub use reader::ReaderOptions;
 pub use reader::Read;
 pub use reader::Reader;
+pub use reader_pool::ReaderPool;
+pub use reader_pool::ReaderPoolOptions;
+pub use reader_pool::ReaderPoolStats;
 pub use sorter::SorterOptions;
 pub use sorter::Sorter;
+pub use source::CustomSource;
+pub use source::SourceImpl;
+pub use source::SourceIter;
+pub use swappable::SwappableReader;
+pub use swappable::SwappableReaderOptions;
 pub use writer::WriterOptions;
 pub use writer::CompressionType;
 pub use writer::Write;
@@ -0,0 +1,253 @@
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::collections::Bound::{Excluded, Included, Unbounded};
+
+use merger::SyncMergeFn;
+use mtbl_sys;
+use reader::{Iter, KeyBounds, Nearest, Query, Read};
+use source::{CustomSource, SourceImpl, SourceIter};
+use writer::Write;
+
+/// A `BTreeMap` can be used directly as a custom MTBL source.
+impl SourceImpl for BTreeMap<Vec<u8>, Vec<u8>> {
+    fn iter(&self) -> SourceIter<'_> {
+        Box::new(BTreeMap::iter(self).map(|(k, v)| (k.clone(), v.clone())))
+    }
+
+    fn get(&self, key: &[u8]) -> SourceIter<'_> {
+        Box::new(self.get_key_value(key).map(|(k, v)| (k.clone(), v.clone())).into_iter())
+    }
+
+    fn get_prefix(&self, prefix: &[u8]) -> SourceIter<'_> {
+        let prefix = prefix.to_vec();
+        Box::new(self.range::<[u8], _>((Included(&prefix[..]), Unbounded))
+                     .take_while(move |&(k, _)| k.starts_with(&prefix))
+                     .map(|(k, v)| (k.clone(), v.clone())))
+    }
+
+    fn get_range(&self, key0: &[u8], key1: &[u8]) -> SourceIter<'_> {
+        if key0 > key1 {
+            return Box::new(None.into_iter());
+        }
+        Box::new(self.range::<[u8], _>((Included(key0), Included(key1)))
+                     .map(|(k, v)| (k.clone(), v.clone())))
+    }
+}
+
+/// An in-memory sorted table, for tests and small overlays.
+///
+/// A `MemTable` is backed by a `BTreeMap`, so keys can be added in any order.
+/// It implements both [`Write`](trait.Write.html) and
+/// [`Read`](trait.Read.html), so it can be merged alongside `Reader`s in a
+/// [`Merger`](struct.Merger.html) and dumped to a
+/// [`Writer`](struct.Writer.html) once it is complete.
+///
+/// When a key is added more than once, the values are combined with the
+/// table's merge function if it has one; otherwise the later value replaces the
+/// earlier one.
+///
+/// # Examples
+///
+/// ```
+/// let mut table = MemTable::new();
+/// table.add("b", "world");
+/// table.add("a", "Hello");
+/// assert_eq!(table.get("a"), Some("Hello".as_bytes().to_vec()));
+/// table.write_to(&mut Writer::create_from_path("/tmp/f.mtbl").unwrap());
+/// ```
+pub struct MemTable {
+    source: CustomSource<BTreeMap<Vec<u8>, Vec<u8>>>,
+    merge_fn: Option<Box<SyncMergeFn>>,
+}
+
+impl MemTable {
+    /// Create an empty `MemTable` in which later values replace earlier ones.
+    pub fn new() -> MemTable {
+        MemTable {
+            source: CustomSource::new(BTreeMap::new()),
+            merge_fn: None,
+        }
+    }
+
+    /// Create an empty `MemTable` that uses a `MergeFn` to combine values for
+    /// colliding keys.
+    pub fn with_merge_fn<F>(merge_fn: F) -> MemTable
+        where F: Fn(&[u8], &[u8], &[u8]) -> Vec<u8> + Send + Sync + 'static
+    {
+        MemTable { merge_fn: Some(Box::new(merge_fn)), ..MemTable::new() }
+    }
+
+    /// The number of entries in the table.
+    pub fn len(&self) -> usize {
+        self.source.inner().len()
+    }
+
+    /// Whether the table has no entries.
+    pub fn is_empty(&self) -> bool {
+        self.source.inner().is_empty()
+    }
+
+    /// Remove a key from the table, returning its value if it was present.
+    pub fn remove<T: AsRef<[u8]>>(&mut self, key: T) -> Option<Vec<u8>> {
+        self.source.inner_mut().remove(key.as_ref())
+    }
+
+    /// Remove all entries from the table.
+    pub fn clear(&mut self) {
+        self.source.inner_mut().clear();
+    }
+
+    /// Write every entry, in sorted order, to a `Writer` (or any other `Write`).
+    ///
+    /// This fails only if the `Writer` rejects an entry, which happens if it
+    /// already holds keys that sort after this table's keys.
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<(), ()> {
+        for (k, v) in self.source.inner() {
+            writer.add(k, v)?;
+        }
+        Ok(())
+    }
+}
+
+impl Default for MemTable {
+    fn default() -> MemTable {
+        MemTable::new()
+    }
+}
+
+impl Write for MemTable {
+    /// Add a key-value pair to the table. Keys may be added in any order, so
+    /// this never fails.
+    fn add<T, U>(&mut self, key: T, value: U) -> Result<(), ()>
+        where T: AsRef<[u8]>,
+              U: AsRef<[u8]>
+    {
+        let key = key.as_ref();
+        let value = value.as_ref();
+        let merged = match (self.source.inner().get(key), &self.merge_fn) {
+            (Some(old), Some(merge_fn)) => merge_fn(key, old, value),
+            _ => value.to_vec(),
+        };
+        self.source.inner_mut().insert(key.to_vec(), merged);
+        Ok(())
+    }
+}
+
+impl Read for MemTable {
+    fn raw_mtbl_source(&self) -> &*const mtbl_sys::mtbl_source {
+        self.source.raw_mtbl_source()
+    }
+
+    fn query(&self, query: Query) -> Iter<'_> {
+        self.source.query(query)
+    }
+
+    /// A `MemTable` can only change through `&mut`.
+    fn content_version(&self) -> Option<u64> {
+        Some(0)
+    }
+
+    fn key_bounds(&self) -> Option<KeyBounds<'_>> {
+        let entries = self.source.inner();
+        let (first, _) = entries.first_key_value()?;
+        let (last, _) = entries.last_key_value()?;
+        Some((Cow::Borrowed(first), Cow::Borrowed(last)))
+    }
+
+    fn nearest(&self, target: Nearest) -> Option<(Vec<u8>, Vec<u8>)> {
+        let entries = self.source.inner();
+        let entry = match target {
+            Nearest::First => entries.iter().next(),
+            Nearest::Last => entries.iter().next_back(),
+            Nearest::Floor(key) => entries.range::<[u8], _>((Unbounded, Included(key))).next_back(),
+            Nearest::Lower(key) => entries.range::<[u8], _>((Unbounded, Excluded(key))).next_back(),
+            Nearest::Ceiling(key) => entries.range::<[u8], _>((Included(key), Unbounded)).next(),
+            Nearest::Higher(key) => entries.range::<[u8], _>((Excluded(key), Unbounded)).next(),
+        };
+        entry.map(|(key, value)| (key.clone(), value.clone()))
+    }
+}
+
+impl<'a> IntoIterator for &'a MemTable {
+    type Item = (Vec<u8>, Vec<u8>);
+    type IntoIter = Iter<'a>;
+
+    fn into_iter(self) -> Iter<'a> {
+        self.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempfile;
+    use self::tempfile::NamedTempFile;
+
+    use memtable::MemTable;
+    use merger::Merger;
+    use reader::{Read, Reader};
+    use test_util::v;
+    use writer::{Write, Writer};
+
+    #[test]
+    fn test_memtable() {
+        let mut table = MemTable::new();
+        assert!(table.is_empty());
+        table.add("two", "world").unwrap();
+        table.add("one", "Hello").unwrap();
+        table.add("three", "green").unwrap();
+        table.add("three", "blue").unwrap();
+        assert_eq!(table.len(), 3);
+        assert_eq!(table.get("a"), None);
+        assert_eq!(table.get("one"), Some(v("Hello")));
+        assert_eq!(table.get("three"), Some(v("blue")));
+        assert_eq!(table.iter().map(|(k, _)| k).collect::<Vec<_>>(),
+                   vec![v("one"), v("three"), v("two")]);
+        assert_eq!(table.get_prefix("t").count(), 2);
+        assert_eq!(table.get_range("o", "three").count(), 2);
+        assert_eq!(table.get_range("z", "a").count(), 0);
+        assert_eq!(table.floor("p"), Some((v("one"), v("Hello"))));
+        assert_eq!(table.lower("three"), Some((v("one"), v("Hello"))));
+        assert_eq!(table.ceiling("three"), Some((v("three"), v("blue"))));
+        assert_eq!(table.higher("two"), None);
+        assert_eq!(table.last(), Some((v("two"), v("world"))));
+        assert_eq!(table.remove("one"), Some(v("Hello")));
+        assert_eq!(table.get("one"), None);
+        table.clear();
+        assert_eq!(table.first(), None);
+        assert_eq!((&table).into_iter().count(), 0);
+    }
+
+    #[test]
+    fn test_merge_fn() {
+        let mut table = MemTable::with_merge_fn(|_key, val0, val1| {
+            let mut merged = val0.to_vec();
+            merged.extend_from_slice(val1);
+            merged
+        });
+        table.add("one", "Hello").unwrap();
+        table.add("one", "world").unwrap();
+        assert_eq!(table.get("one"), Some(v("Helloworld")));
+    }
+
+    #[test]
+    fn test_merge_with_reader_and_write_to() {
+        let tempfile_writer = NamedTempFile::new().unwrap();
+        let tempfile_reader = tempfile_writer.reopen().unwrap();
+        let mut base = MemTable::new();
+        base.add("one", "Hello").unwrap();
+        base.add("two", "world").unwrap();
+        {
+            let mut writer = Writer::create_from_file(tempfile_writer).unwrap();
+            base.write_to(&mut writer).unwrap();
+        }
+        let reader = Reader::open_from_file(&tempfile_reader).unwrap();
+        assert_eq!(reader.iter().collect::<Vec<_>>(), base.iter().collect::<Vec<_>>());
+
+        let mut overlay = MemTable::new();
+        overlay.add("two", "blue").unwrap();
+        let sources: Vec<Box<dyn Read>> = vec![Box::new(reader), Box::new(overlay)];
+        let merger = Merger::new(sources, Merger::merge_choose_last_value);
+        assert_eq!(merger.get("one"), Some(v("Hello")));
+        assert_eq!(merger.get("two"), Some(v("blue")));
+    }
+}
@@ -0,0 +1,315 @@
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::Result as IOResult;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use merger::{Merger, SyncMergeFn};
+use reader::{Read, Reader};
+use writer::{Write, Writer, WriterOptions};
+
+/// The most files merged at once by default.
+const DEFAULT_MAX_FAN_IN: usize = 64;
+
+/// Distinguishes the intermediate files of planners in the same process.
+static NEXT_TEMP_FILE: AtomicUsize = AtomicUsize::new(0);
+
+/// Options used to create a `MergePlanner`.
+#[derive(Clone)]
+pub struct MergePlannerOptions {
+    /// The temporary directory to be used for intermediate files. Default is "/var/tmp".
+    pub temp_dir: Option<PathBuf>,
+    /// The most files to open and merge at once. Default is 64.
+    pub max_fan_in: Option<usize>,
+    /// The options used to write intermediate files. Default is the `Writer` defaults.
+    pub writer_options: Option<WriterOptions>,
+}
+
+impl MergePlannerOptions {
+    /// Create a new `MergePlannerOptions` with defaults.
+    pub fn new() -> MergePlannerOptions {
+        MergePlannerOptions {
+            temp_dir: None,
+            max_fan_in: None,
+            writer_options: None,
+        }
+    }
+
+    /// Create a new `MergePlannerOptions` with temp_dir set.
+    pub fn temp_dir<T: AsRef<Path>>(self: &Self, path: T) -> MergePlannerOptions {
+        MergePlannerOptions { temp_dir: Some(path.as_ref().to_path_buf()), ..self.clone() }
+    }
+
+    /// Create a new `MergePlannerOptions` with max_fan_in set. Values below 2
+    /// are treated as 2.
+    pub fn max_fan_in(self: &Self, max_fan_in: usize) -> MergePlannerOptions {
+        MergePlannerOptions { max_fan_in: Some(max_fan_in), ..self.clone() }
+    }
+
+    /// Create a new `MergePlannerOptions` with writer_options set.
+    pub fn writer_options(self: &Self, writer_options: WriterOptions) -> MergePlannerOptions {
+        MergePlannerOptions { writer_options: Some(writer_options), ..self.clone() }
+    }
+
+    /// Create a new `MergePlanner` with these options. Note that a `MergeFn`
+    /// must be supplied to combine values for entries with colliding keys.
+    pub fn create<F>(self: &Self, merge_fn: F) -> MergePlanner
+        where F: Fn(&[u8], &[u8], &[u8]) -> Vec<u8> + Send + Sync + 'static
+    {
+        MergePlanner {
+            options: self.clone(),
+            merge_fn: Arc::new(merge_fn),
+        }
+    }
+}
+
+/// A tool to merge more MTBL files than can be opened at once.
+///
+/// A `Merger` keeps every input open, so merging thousands of files needs
+/// thousands of file descriptors and memory maps. A `MergePlanner` instead
+/// merges in passes: each pass merges runs of at most `max_fan_in`
+/// neighbouring inputs into intermediate files in the temporary directory,
+/// until few enough are left to merge into the output. Intermediate files are
+/// deleted as soon as the next pass has read them.
+///
+/// Because runs are merged in input order, colliding values are combined in
+/// the same order as a `Merger` over all the inputs would, but grouped
+/// differently, so the `MergeFn` must be associative (as choosing the first or
+/// last value is) for the result to be the same.
+///
+/// # Example
+///
+/// ```
+/// let planner = MergePlannerOptions::new().max_fan_in(100).create(Merger::merge_choose_last_value);
+/// planner.merge_to_path(&inputs, "/srv/merged.mtbl")?;
+/// ```
+pub struct MergePlanner {
+    /// The options used to create this planner.
+    pub options: MergePlannerOptions,
+    merge_fn: Arc<SyncMergeFn>,
+}
+
+impl MergePlanner {
+    /// Create a new `MergePlanner`. Note that a `MergeFn` must be supplied to
+    /// combine values for entries with colliding keys.
+    pub fn new<F>(merge_fn: F) -> MergePlanner
+        where F: Fn(&[u8], &[u8], &[u8]) -> Vec<u8> + Send + Sync + 'static
+    {
+        MergePlannerOptions::new().create(merge_fn)
+    }
+
+    /// The number of passes needed to merge `inputs` files, counting the
+    /// final merge into the output.
+    pub fn passes(&self, inputs: usize) -> usize {
+        let mut remaining = inputs;
+        let mut passes = 1;
+        while remaining > self.max_fan_in() {
+            remaining = plan_pass(remaining, self.max_fan_in()).len();
+            passes += 1;
+        }
+        passes
+    }
+
+    /// Merge the MTBL files at `inputs` into a new MTBL file at `output`.
+    ///
+    /// The output is written to a temporary file in the same directory and
+    /// renamed into place once complete, so `output` is never left partly
+    /// written: if the merge fails, it is left as it was.
+    pub fn merge_to_path<T, U>(&self, inputs: &[T], output: U) -> IOResult<()>
+        where T: AsRef<Path>,
+              U: AsRef<Path>
+    {
+        let output = output.as_ref();
+        let name = output.file_name().ok_or_else(|| {
+            Error::new(ErrorKind::InvalidInput, "output path has no file name")
+        })?;
+        let mut temp_name = OsString::from(".");
+        temp_name.push(name);
+        temp_name.push(format!(".{}.{}.tmp",
+                               process::id(),
+                               NEXT_TEMP_FILE.fetch_add(1, Ordering::SeqCst)));
+        let temp = TempFile(output.with_file_name(temp_name));
+        // Dropping the writer finishes the file.
+        self.merge_to_writer(inputs, Writer::create_from_path(&temp.0)?)?;
+        File::open(&temp.0)?.sync_all()?;
+        temp.rename(output)
+    }
+
+    /// Merge the MTBL files at `inputs`, writing the entries to `writer`.
+    ///
+    /// This will result in an Error of kind `InvalidInput` if the `Writer`
+    /// receives items out of order, which can only happen if it already had
+    /// items added.
+    pub fn merge_to_writer<T: AsRef<Path>>(&self, inputs: &[T], mut writer: Writer) -> IOResult<()> {
+        let mut level: Vec<Input> = inputs.iter()
+                                          .map(|p| Input::Original(p.as_ref().to_path_buf()))
+                                          .collect();
+        while level.len() > self.max_fan_in() {
+            let mut next = Vec::new();
+            let mut rest = level.into_iter();
+            for run in plan_pass(rest.len(), self.max_fan_in()) {
+                let run: Vec<Input> = rest.by_ref().take(run).collect();
+                if run.len() == 1 {
+                    next.extend(run);
+                    continue;
+                }
+                let temp = TempFile(self.temp_path());
+                let options = self.options.writer_options.unwrap_or_else(WriterOptions::new);
+                self.merge_run(&run, &mut options.create_from_path(&temp.0)?)?;
+                next.push(Input::Intermediate(temp));
+            }
+            level = next;
+        }
+        self.merge_run(&level, &mut writer)
+    }
+
+    fn max_fan_in(&self) -> usize {
+        self.options.max_fan_in.unwrap_or(DEFAULT_MAX_FAN_IN).max(2)
+    }
+
+    fn temp_path(&self) -> PathBuf {
+        let dir = self.options.temp_dir.clone().unwrap_or_else(|| PathBuf::from("/var/tmp"));
+        dir.join(format!(".mtbl-merge-{}-{}.mtbl",
+                         process::id(),
+                         NEXT_TEMP_FILE.fetch_add(1, Ordering::SeqCst)))
+    }
+
+    /// Merge one run of inputs into `writer`.
+    fn merge_run<W: Write>(&self, run: &[Input], writer: &mut W) -> IOResult<()> {
+        let readers = run.iter()
+                         .map(|input| Reader::open_from_path(input.path()))
+                         .collect::<IOResult<Vec<Reader>>>()?;
+        let merge_fn = self.merge_fn.clone();
+        let merger = Merger::new(readers, move |key, val0, val1| merge_fn(key, val0, val1));
+        for (key, value) in merger.iter() {
+            writer.add(key, value).map_err(|_| {
+                Error::new(ErrorKind::InvalidInput, "output Writer received entries out of order")
+            })?;
+        }
+        Ok(())
+    }
+}
+
+/// A file being merged.
+enum Input {
+    /// One of the files given to the planner.
+    Original(PathBuf),
+    /// A file written by an earlier pass.
+    Intermediate(TempFile),
+}
+
+impl Input {
+    fn path(&self) -> &Path {
+        match *self {
+            Input::Original(ref path) => path,
+            Input::Intermediate(ref temp) => &temp.0,
+        }
+    }
+}
+
+/// An intermediate file, deleted when dropped unless renamed.
+struct TempFile(PathBuf);
+
+impl TempFile {
+    /// Move the file to `path`, so that it is kept.
+    fn rename(mut self, path: &Path) -> IOResult<()> {
+        fs::rename(&self.0, path)?;
+        self.0 = PathBuf::new();
+        Ok(())
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if !self.0.as_os_str().is_empty() {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+}
+
+/// Split `inputs` files into as few runs of at most `max_fan_in` as possible,
+/// with sizes as even as possible, returning the size of each run.
+fn plan_pass(inputs: usize, max_fan_in: usize) -> Vec<usize> {
+    let runs = inputs.div_ceil(max_fan_in);
+    (0..runs).map(|i| inputs / runs + if i < inputs % runs { 1 } else { 0 }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+
+    use merge_planner::{plan_pass, MergePlanner, MergePlannerOptions};
+    use merger::Merger;
+    use reader::{Read, Reader};
+    use test_util::TempDir;
+    use writer::{Write, Writer};
+
+    #[test]
+    fn test_plan_pass() {
+        assert_eq!(plan_pass(10, 4), vec![4, 3, 3]);
+        assert_eq!(plan_pass(8, 4), vec![4, 4]);
+        assert_eq!(plan_pass(3, 4), vec![3]);
+        let planner = MergePlannerOptions::new().max_fan_in(10).create(Merger::merge_choose_last_value);
+        assert_eq!(planner.passes(10), 1);
+        assert_eq!(planner.passes(11), 2);
+        assert_eq!(planner.passes(1000), 3);
+    }
+
+    #[test]
+    fn test_merge_planner() {
+        let dir = TempDir::new();
+        let temp_dir = dir.path().join("tmp");
+        fs::create_dir(&temp_dir).unwrap();
+        // Each input has one key of its own and a value for the shared key "k".
+        let inputs: Vec<PathBuf> = (0..50)
+                                       .map(|i| {
+                                           let path = dir.path().join(format!("{:02}.mtbl", i));
+                                           let mut writer = Writer::create_from_path(&path)
+                                                                .unwrap();
+                                           writer.add("k", format!("{},", i)).unwrap();
+                                           writer.add(format!("key{:02}", i), "x").unwrap();
+                                           path
+                                       })
+                                       .collect();
+        let concat = |_: &[u8], val0: &[u8], val1: &[u8]| [val0, val1].concat();
+        let planner = MergePlannerOptions::new()
+                          .max_fan_in(4)
+                          .temp_dir(&temp_dir)
+                          .create(concat);
+        assert_eq!(planner.passes(inputs.len()), 4);
+        let output = dir.path().join("merged.mtbl");
+        planner.merge_to_path(&inputs, &output).unwrap();
+        assert_eq!(fs::read_dir(&temp_dir).unwrap().count(), 0);
+
+        let merged = Reader::open_from_path(&output).unwrap();
+        let all_at_once = Merger::new(inputs.iter().map(|p| Reader::open_from_path(p).unwrap())
+                                            .collect(),
+                                      concat);
+        assert_eq!(merged.iter().collect::<Vec<_>>(),
+                   all_at_once.iter().collect::<Vec<_>>());
+        assert_eq!(merged.get("k").unwrap(),
+                   (0..50).map(|i| format!("{},", i)).collect::<String>().into_bytes());
+
+        // Missing inputs are reported, and intermediate files and the partial
+        // output cleaned up.
+        let mut missing = inputs.clone();
+        missing.push(dir.path().join("missing.mtbl"));
+        let failed = dir.path().join("failed.mtbl");
+        assert!(MergePlanner::new(Merger::merge_choose_last_value)
+                    .merge_to_path(&missing, &failed)
+                    .is_err());
+        assert!(planner.merge_to_path(&missing, &failed).is_err());
+        assert!(!failed.exists());
+        assert_eq!(fs::read_dir(&temp_dir).unwrap().count(), 0);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), inputs.len() + 2);
+
+        // A failed merge leaves an existing output as it was.
+        assert!(planner.merge_to_path(&missing, &output).is_err());
+        assert_eq!(Reader::open_from_path(&output).unwrap().iter().collect::<Vec<_>>(),
+                   merged.iter().collect::<Vec<_>>());
+    }
+}
@@ -2,8 +2,11 @@ use libc::{c_void, malloc, size_t};
 use std;
 use std::slice;
 
+use grouped_merger::GroupedIter;
 use mtbl_sys;
-use reader::{Iter, Read, Reader};
+use reader::{Iter, Nearest, Query, Read};
+use source::NO_MTBL_SOURCE;
+use tombstone::{self, Tombstoned};
 
 /// An MTBL merging function: given a key and two values for that key, create a
 /// merged value for that key.
@@ -14,36 +17,59 @@ use reader::{Iter, Read, Reader};
 /// via the FFI (Foreign Function Interface).
 pub type MergeFn = Fn(&[u8], &[u8], &[u8]) -> Vec<u8>;
 
+/// A `MergeFn` that can be shared between threads.
+pub type SyncMergeFn = dyn Fn(&[u8], &[u8], &[u8]) -> Vec<u8> + Send + Sync;
+
 /// An MTBL reader that opens and reads from several MTBL files, merging their
 /// contents.
 ///
 /// An MTBL can have only one value for a key, so when the inputs have a key
 /// collision (more than one source contains the same key), it uses a
 /// [`MergeFn`](type.MergeFn.html) to combine them.
-pub struct Merger {
-    _sources: Vec<Box<Read>>,
+///
+/// A `Merger` owns its sources. A plain `Merger` holds boxed trait objects, so
+/// that any kind of source (`Reader`s, `Fileset`s, `MemTable`s, other
+/// `Merger`s, ...) can be added to it; a `Merger<S>` holds sources of one type
+/// `S`, such as `Merger<Reader>`. Sources may also be borrowed, in which case
+/// the `Merger` cannot outlive them. Sources that are all backed by libmtbl are
+/// merged by libmtbl; once any is implemented in Rust (a `MemTable`, say), the
+/// merging is done in Rust.
+///
+/// # Examples
+///
+/// ```
+/// let live = Fileset::open_from_path("/srv/live.mtbl-fileset", merge_fn);
+/// let base = Reader::open_from_path("/srv/base.mtbl").unwrap();
+/// let sources: Vec<Box<dyn Read + '_>> = vec![Box::new(base), Box::new(&live)];
+/// let mut merger = Merger::new(sources, Merger::merge_choose_last_value);
+/// merger.add_source(MemTable::new());
+/// ```
+pub struct Merger<S: Read = Box<dyn Read>> {
+    _sources: Vec<S>,
     // NOTE(leon, 2015-12-13): I haven't figured out a better way to pass this
     // function to C than with nested boxes. Help would be appreciated.
     /// The function used to combine values for colliding keys.
-    pub merge_fn: Box<Box<MergeFn>>,
+    pub merge_fn: Box<Box<SyncMergeFn>>,
     mtbl_merger: *mut mtbl_sys::mtbl_merger,
     mtbl_source: *const mtbl_sys::mtbl_source,
 }
 
-pub extern "C" fn _merge_cb_shim(clos: *mut c_void,
-                                 key: *const u8,
-                                 len_key: size_t,
-                                 val0: *const u8,
-                                 len_val0: size_t,
-                                 val1: *const u8,
-                                 len_val1: size_t,
-                                 merged_val: *mut *mut u8,
-                                 len_merged_val: *mut size_t) {
+pub extern "C" fn _merge_cb_shim<F>(clos: *mut c_void,
+                                    key: *const u8,
+                                    len_key: size_t,
+                                    val0: *const u8,
+                                    len_val0: size_t,
+                                    val1: *const u8,
+                                    len_val1: size_t,
+                                    merged_val: *mut *mut u8,
+                                    len_merged_val: *mut size_t)
+    where F: Fn(&[u8], &[u8], &[u8]) -> Vec<u8> + ?Sized
+{
     unsafe {
         // NOTE(leon, 2015-12-13): I would like to simplify this so I don't have
         // to use quite as many layers of pointers, but I'm not sure how. Help
         // would be appreciated.
-        let merge_fn: &mut Box<MergeFn> = &mut *(clos as *mut Box<MergeFn>);
+        let merge_fn: &mut Box<F> = &mut *(clos as *mut Box<F>);
         let merged = merge_fn(slice::from_raw_parts(key, len_key),
                               slice::from_raw_parts(val0, len_val0),
                               slice::from_raw_parts(val1, len_val1));
@@ -55,6 +81,19 @@ pub extern "C" fn _merge_cb_shim(clos: *mut c_void,
     }
 }
 
+/// Create an mtbl_merger that combines values with `merge_fn`, which must
+/// outlive it.
+unsafe fn new_mtbl_merger(merge_fn: *const Box<SyncMergeFn>) -> *mut mtbl_sys::mtbl_merger {
+    let mut opts = mtbl_sys::mtbl_merger_options_init();
+    // Wacky casting to get a void pointer for the C lib.
+    mtbl_sys::mtbl_merger_options_set_merge_func(opts,
+                                                 _merge_cb_shim::<SyncMergeFn>,
+                                                 merge_fn as *mut c_void);
+    let mtbl_merger = mtbl_sys::mtbl_merger_init(opts);
+    mtbl_sys::mtbl_merger_options_destroy(&mut opts);
+    mtbl_merger
+}
+
 impl Merger {
     /// A default MTBL merging function that chooses the last (second) value for the colliding key.
     pub fn merge_choose_last_value(_key: &[u8], _val0: &[u8], val1: &[u8]) -> Vec<u8> {
@@ -66,50 +105,169 @@ impl Merger {
         val0.to_vec()
     }
 
+    /// Add an additional source of data to be merged.
+    pub fn add_source<T: 'static + Read>(self: &mut Self, source: T) {
+        self.push_source(Box::new(source));
+    }
+}
+
+impl<S: Read> Merger<S> {
     /// Create a merger from a collection of other sources. Note that you must provide a merge_fn
     /// to combine values for colliding keys.
-    pub fn new<F>(sources: Vec<Reader>, merge_fn: F) -> Merger
-        where F: Fn(&[u8], &[u8], &[u8]) -> Vec<u8> + 'static
+    ///
+    /// When the same key appears in several sources, values are merged in the order the sources
+    /// were given. The merge_fn must be `Send + Sync`, since the merger may be shared between
+    /// threads.
+    pub fn new<F>(sources: Vec<S>, merge_fn: F) -> Merger<S>
+        where F: Fn(&[u8], &[u8], &[u8]) -> Vec<u8> + Send + Sync + 'static
     {
-        let mut merge_fn: Box<Box<MergeFn>> = Box::new(Box::new(merge_fn));
+        let merge_fn: Box<Box<SyncMergeFn>> = Box::new(Box::new(merge_fn));
         unsafe {
-            let mut opts = mtbl_sys::mtbl_merger_options_init();
-            mtbl_sys::mtbl_merger_options_set_merge_func(opts,
-                                                         _merge_cb_shim,
-                                                         // Wacky casting to get a void pointer for
-                                                         // the C lib.
-                                                         &mut (*merge_fn) as *mut _ as *mut c_void);
-            let mtbl_merger = mtbl_sys::mtbl_merger_init(opts);
+            let mtbl_merger = new_mtbl_merger(&*merge_fn);
             let mut merger = Merger {
                 _sources: Vec::new(),
                 merge_fn: merge_fn,
                 mtbl_merger: mtbl_merger,
                 mtbl_source: mtbl_sys::mtbl_merger_source(mtbl_merger),
             };
-            mtbl_sys::mtbl_merger_options_destroy(&mut opts);
             for source in sources {
-                merger.add_source(source)
+                merger.push_source(source)
             }
             merger
         }
     }
 
+    /// Create a merger of tombstone-encoded sources, merged with
+    /// [`tombstone::merge`](tombstone/fn.merge.html), that reads the live keys
+    /// and values.
+    pub fn tombstoned(sources: Vec<S>) -> Tombstoned<Merger<S>> {
+        Tombstoned::new(Merger::new(sources, tombstone::merge))
+    }
+
     /// Add an additional source of data to be merged.
-    pub fn add_source<T: 'static + Read>(self: &mut Self, source: T) {
-        unsafe {
-            mtbl_sys::mtbl_merger_add_source(self.mtbl_merger, *source.raw_mtbl_source());
+    pub fn push_source(self: &mut Self, source: S) {
+        let mtbl_source = *source.raw_mtbl_source();
+        if !mtbl_source.is_null() {
+            unsafe {
+                mtbl_sys::mtbl_merger_add_source(self.mtbl_merger, mtbl_source);
+            }
         }
-        self._sources.push(Box::new(source));
+        self._sources.push(source);
+    }
+
+    /// The sources being merged, in the order they were added.
+    pub fn sources(&self) -> &[S] {
+        &self._sources
+    }
+
+    /// Whether every source has an mtbl_source, so that libmtbl can do the
+    /// merging; otherwise it's done in Rust.
+    fn is_native(&self) -> bool {
+        self._sources.iter().all(|s| !s.raw_mtbl_source().is_null())
+    }
+
+    /// Merge the entries of several sources in Rust, combining the values of
+    /// colliding keys in source order.
+    fn merge_entries<'a>(&'a self, iters: Vec<Iter<'a>>) -> Iter<'a> {
+        let merge_fn = &self.merge_fn;
+        Iter::from_entries(Box::new(GroupedIter::new(iters).map(move |(key, values)| {
+            let mut values = values.into_iter().map(|(_, value)| value);
+            let first = values.next().unwrap_or_default();
+            let merged = values.fold(first, |merged, value| merge_fn(&key, &merged, &value));
+            (key, merged)
+        })))
     }
 }
 
-impl Read for Merger {
+/// Lookups skip the sources whose [`key_bounds`](trait.Read.html#method.key_bounds)
+/// show that they cannot contain the keys asked for. Full iteration always
+/// reads every source.
+impl<S: Read> Read for Merger<S> {
+    /// Null unless every source has an mtbl_source of its own.
     fn raw_mtbl_source(&self) -> &*const mtbl_sys::mtbl_source {
-        &self.mtbl_source
+        if self.is_native() {
+            &self.mtbl_source
+        } else {
+            &NO_MTBL_SOURCE
+        }
+    }
+
+    /// Runs the lookup against only the sources whose key bounds overlap the
+    /// keys it asks for; sources with unknown bounds are always queried.
+    fn query(&self, query: Query) -> Iter<'_> {
+        let selected: Vec<&S> = self._sources
+                                    .iter()
+                                    .filter(|s| {
+                                        s.key_bounds()
+                                         .is_none_or(|(first, last)| query.overlaps(&first, &last))
+                                    })
+                                    .collect();
+        if !selected.iter().all(|s| !s.raw_mtbl_source().is_null()) {
+            return self.merge_entries(selected.into_iter().map(|s| s.query(query)).collect());
+        }
+        if selected.len() == self._sources.len() {
+            return Iter::new(unsafe { query.run(self.mtbl_source) }, &self.mtbl_source);
+        }
+        match selected.len() {
+            0 => Iter::empty(),
+            1 => Iter::new(unsafe { query.run(*selected[0].raw_mtbl_source()) }, &self.mtbl_source),
+            _ => unsafe {
+                let mtbl_merger = new_mtbl_merger(&*self.merge_fn);
+                for source in selected {
+                    mtbl_sys::mtbl_merger_add_source(mtbl_merger, *source.raw_mtbl_source());
+                }
+                let mtbl_iter = query.run(mtbl_sys::mtbl_merger_source(mtbl_merger));
+                Iter::with_merger(mtbl_iter, mtbl_merger, &self.mtbl_source)
+            },
+        }
+    }
+
+    /// The sum of the sources' versions, which increases whenever one of
+    /// theirs does.
+    fn content_version(&self) -> Option<u64> {
+        self._sources.iter().map(|s| s.content_version()).sum()
+    }
+
+    /// Looks the keys up in each source that may contain some of them, and
+    /// merges the values found in source order.
+    fn get_sorted(&self, keys: &[&[u8]]) -> Vec<Option<Vec<u8>>> {
+        let mut values: Vec<Option<Vec<u8>>> = vec![None; keys.len()];
+        let (first, last) = match (keys.first(), keys.last()) {
+            (Some(first), Some(last)) => (*first, *last),
+            _ => return values,
+        };
+        for source in &self._sources {
+            if source.key_bounds().is_some_and(|(lo, hi)| &hi[..] < first || &lo[..] > last) {
+                continue;
+            }
+            for ((key, value), found) in keys.iter().zip(&mut values).zip(source.get_sorted(keys)) {
+                if let Some(found) = found {
+                    *value = Some(match value.take() {
+                        Some(merged) => (self.merge_fn)(key, &merged, &found),
+                        None => found,
+                    });
+                }
+            }
+        }
+        values
+    }
+
+    /// Finds the nearest key in each source that may contain it, and merges
+    /// the values of the nearest of those.
+    fn nearest(&self, target: Nearest) -> Option<(Vec<u8>, Vec<u8>)> {
+        let keys = self._sources
+                       .iter()
+                       .filter(|source| {
+                           !source.key_bounds().is_some_and(|(lo, hi)| target.excludes(&lo, &hi))
+                       })
+                       .filter_map(|source| source.nearest(target).map(|(key, _)| key));
+        let key = if target.is_forward() { keys.min() } else { keys.max() }?;
+        let value = self.get(&key)?;
+        Some((key, value))
     }
 }
 
-impl<'a> IntoIterator for &'a Merger {
+impl<'a, S: Read> IntoIterator for &'a Merger<S> {
     type Item = (Vec<u8>, Vec<u8>);
     type IntoIter = Iter<'a>;
 
@@ -121,7 +279,7 @@ impl<'a> IntoIterator for &'a Merger {
 // Implement IntoIterator for Merger? I'm not sure how to cleanly do the needed lifetime stuff,
 // though.
 
-impl Drop for Merger {
+impl<S: Read> Drop for Merger<S> {
     fn drop(&mut self) {
         unsafe {
             mtbl_sys::mtbl_merger_destroy(&mut self.mtbl_merger);
@@ -129,44 +287,26 @@ impl Drop for Merger {
     }
 }
 
-/// Merger is thread-safe.
-unsafe impl Send for Merger {}
+/// Merger is thread-safe if its sources are, since its merge function is
+/// required to be.
+unsafe impl<S: Read + Send> Send for Merger<S> {}
 
-/// Merger is thread-safe.
-unsafe impl Sync for Merger {}
+/// Merger is thread-safe if its sources are, since its merge function is
+/// required to be.
+unsafe impl<S: Read + Sync> Sync for Merger<S> {}
 
 #[cfg(test)]
 mod tests {
-    extern crate tempfile;
-    use self::tempfile::NamedTempFile;
-
+    use memtable::MemTable;
     use merger::Merger;
+    use mtbl_sys;
     use reader;
     use reader::Read as iRead;
-    use writer;
+    use reader::KeyBounds;
+    use std::thread;
+    use test_util::set_up_readers;
     use writer::Write as iWrite;
 
-    /// Set up readers with collision for "one".
-    fn set_up_readers() -> Vec<reader::Reader> {
-        let tempfile_writer1 = NamedTempFile::new().unwrap();
-        let tempfile_reader1 = tempfile_writer1.reopen().unwrap();
-        {
-            let mut writer = writer::Writer::create_from_file(tempfile_writer1).unwrap();
-            writer.add("one", "Hello").unwrap();
-            writer.add("two", "world").unwrap();
-        }
-        let tempfile_writer2 = NamedTempFile::new().unwrap();
-        let tempfile_reader2 = tempfile_writer2.reopen().unwrap();
-        {
-            let mut writer = writer::Writer::create_from_file(tempfile_writer2).unwrap();
-            writer.add("one", "blue").unwrap();
-            writer.add("three", "green").unwrap();
-        }
-        let reader1 = reader::Reader::open_from_file(&tempfile_reader1).unwrap();
-        let reader2 = reader::Reader::open_from_file(&tempfile_reader2).unwrap();
-        vec![reader1, reader2]
-    }
-
     #[test]
     fn test_merger() {
         let merger = Merger::new(set_up_readers(),
@@ -198,4 +338,133 @@ mod tests {
         assert_eq!(merger.get("three").unwrap(), "green".as_bytes());
     }
 
+    #[test]
+    fn test_heterogeneous_sources() {
+        let mut readers = set_up_readers();
+        let last = readers.pop().unwrap();
+        let inner = Merger::new(readers, Merger::merge_choose_first_value);
+        {
+            // A borrowed source, layered over an owned `Merger`.
+            let sources: Vec<Box<dyn iRead + '_>> = vec![Box::new(inner), Box::new(&last)];
+            let merger = Merger::new(sources, |_key, val0, val1| {
+                let mut merged = val0.to_vec();
+                merged.extend_from_slice(val1);
+                merged
+            });
+            assert_eq!(merger.get("a"), None);
+            assert_eq!(merger.get("one").unwrap(), "Helloblue".as_bytes());
+            assert_eq!(merger.get("two").unwrap(), "world".as_bytes());
+            assert_eq!(merger.get("three").unwrap(), "green".as_bytes());
+            assert_eq!(merger.sources().len(), 2);
+            assert_eq!(merger.iter().count(), 3);
+        }
+        assert_eq!(last.get("one").unwrap(), "blue".as_bytes());
+    }
+
+    #[test]
+    fn test_send_sync_sources() {
+        let mut sources: Vec<Box<dyn iRead + Send + Sync>> = Vec::new();
+        for reader in set_up_readers() {
+            sources.push(Box::new(reader));
+        }
+        let merger = Merger::new(sources, Merger::merge_choose_last_value);
+        let value = thread::spawn(move || merger.get("one")).join().unwrap();
+        assert_eq!(value.unwrap(), "blue".as_bytes());
+    }
+
+    #[test]
+    fn test_rust_sources() {
+        let mut table = MemTable::new();
+        table.add("one", "red").unwrap();
+        table.add("zero", "black").unwrap();
+        // A plain `Merger` takes any kind of source.
+        let mut merger: Merger = Merger::new(Vec::new(), |_key, val0, val1| {
+            [val0, val1].join(&b'+')
+        });
+        for reader in set_up_readers() {
+            merger.add_source(reader);
+        }
+        merger.add_source(table);
+        // A MemTable has no mtbl_source, so neither does the merger.
+        assert!(merger.raw_mtbl_source().is_null());
+        assert_eq!(merger.get("one").unwrap(), "Hello+blue+red".as_bytes());
+        assert_eq!(merger.get("zero").unwrap(), "black".as_bytes());
+        assert_eq!(merger.get_range("p", "zz").count(), 3);
+        let keys: Vec<Vec<u8>> = merger.iter().map(|(k, _)| k).collect();
+        assert_eq!(keys,
+                   vec![b"one".to_vec(), b"three".to_vec(), b"two".to_vec(), b"zero".to_vec()]);
+        let boxed: Vec<Box<dyn iRead>> = vec![Box::new(merger)];
+        let nested = Merger::new(boxed, Merger::merge_choose_last_value);
+        assert_eq!(nested.get("one").unwrap(), "Hello+blue+red".as_bytes());
+    }
+
+    /// A source that claims to hold only the keys between two bounds.
+    struct Bounded(reader::Reader, &'static str, &'static str);
+
+    impl iRead for Bounded {
+        fn raw_mtbl_source(&self) -> &*const mtbl_sys::mtbl_source {
+            self.0.raw_mtbl_source()
+        }
+
+        fn key_bounds(&self) -> Option<KeyBounds<'_>> {
+            Some((self.1.as_bytes().into(), self.2.as_bytes().into()))
+        }
+    }
+
+    #[test]
+    fn test_key_bounds_pruning() {
+        let readers = set_up_readers();
+        assert_eq!(readers[0].key_bounds(),
+                   Some(("one".as_bytes().into(), "two".as_bytes().into())));
+        let merger = Merger::new(readers, Merger::merge_choose_last_value);
+        assert_eq!(merger.get("one").unwrap(), "blue".as_bytes());
+        assert_eq!(merger.get_prefix("t").count(), 2);
+        assert_eq!(merger.get_range("p", "z").count(), 2);
+        assert_eq!(merger.get_range("a", "b").count(), 0);
+
+        // Sources whose bounds exclude a key are not consulted for it.
+        let mut readers = set_up_readers().into_iter();
+        let sources = vec![Bounded(readers.next().unwrap(), "one", "two"),
+                           Bounded(readers.next().unwrap(), "three", "three")];
+        let merger = Merger::new(sources, Merger::merge_choose_last_value);
+        assert_eq!(merger.get("one").unwrap(), "Hello".as_bytes());
+        assert_eq!(merger.get("three").unwrap(), "green".as_bytes());
+        assert_eq!(merger.get_prefix("o").collect::<Vec<_>>(),
+                   vec![("one".as_bytes().to_vec(), "Hello".as_bytes().to_vec())]);
+        assert_eq!(merger.get_range("a", "p").count(), 1);
+        assert_eq!(merger.get_range("o", "tz").count(), 3);
+        assert_eq!(merger.get_range("u", "z").count(), 0);
+        // Iterating over everything still reads every source.
+        assert_eq!(merger.iter().count(), 3);
+    }
+
+    #[test]
+    fn test_get_many() {
+        let merger = Merger::new(set_up_readers(), |_key, val0, val1| [val0, val1].join(&b'+'));
+        let keys = ["two", "missing", "one", "three", "one"];
+        let expected: Vec<_> = keys.iter().map(|k| merger.get(k)).collect();
+        assert_eq!(expected[2], Some("Hello+blue".as_bytes().to_vec()));
+        assert_eq!(merger.get_many(keys), expected);
+        let boxed: Vec<Box<dyn iRead>> = vec![Box::new(merger)];
+        let nested = Merger::new(boxed, Merger::merge_choose_last_value);
+        assert_eq!(nested.get_many(keys), expected);
+    }
+
+    #[test]
+    fn test_nearest() {
+        let merger = Merger::new(set_up_readers(), |_key, val0, val1| [val0, val1].join(&b'+'));
+        let entry = |k: &str, v: &str| Some((k.as_bytes().to_vec(), v.as_bytes().to_vec()));
+        assert_eq!(merger.first(), entry("one", "Hello+blue"));
+        assert_eq!(merger.last(), entry("two", "world"));
+        assert_eq!(merger.floor("one"), entry("one", "Hello+blue"));
+        assert_eq!(merger.floor("thr"), entry("one", "Hello+blue"));
+        assert_eq!(merger.lower("one"), None);
+        assert_eq!(merger.lower("two"), entry("three", "green"));
+        assert_eq!(merger.ceiling("p"), entry("three", "green"));
+        assert_eq!(merger.higher("three"), entry("two", "world"));
+        assert_eq!(merger.higher("two"), None);
+        let boxed: Vec<Box<dyn iRead>> = vec![Box::new(merger)];
+        let nested = Merger::new(boxed, Merger::merge_choose_last_value);
+        assert_eq!(nested.ceiling("o"), entry("one", "Hello+blue"));
+    }
 }
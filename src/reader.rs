@@ -1,13 +1,27 @@
-use libc::size_t;
+use libc::{self, size_t};
+use std::borrow::Cow;
 use std::fs::File;
+use std::marker::PhantomData;
 use std::io::Result as IOResult;
 use std::io::{Error, ErrorKind};
-use std::os::unix::io::AsRawFd;
+#[cfg(feature = "rayon")]
+use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd};
 use std::path::Path;
 use std::ptr;
+use std::rc::Rc;
 use std::slice;
+use std::sync::{Arc, OnceLock};
+use std::thread::{self, JoinHandle};
 
+use format::{self, Block, FormatVersion, Mmap};
+use inspect::{self, Blocks, IndexEntries, IndexEntry, VerifyReport};
 use mtbl_sys;
+#[cfg(feature = "rayon")]
+use rayon;
+use source::SourceIter;
+#[cfg(feature = "rayon")]
+use rayon::prelude::*;
 
 pub use mtbl_sys::CompressionType;
 
@@ -18,43 +32,131 @@ pub use mtbl_sys::CompressionType;
 /// Because it's a sorted map, you can access not just via an exact key but also
 /// by a key prefix or range.
 pub trait Read {
-    /// Get the internal mtbl_source pointer.
+    /// Get the internal mtbl_source pointer. This is null for sources
+    /// implemented in Rust, which answer [`query`](#method.query) themselves.
     fn raw_mtbl_source(&self) -> &*const mtbl_sys::mtbl_source;
 
+    /// Run a lookup, returning an iterator over the entries it finds. All the
+    /// other lookups go through this. The default runs the lookup against the
+    /// source from [`raw_mtbl_source`](#method.raw_mtbl_source); sources
+    /// implemented in Rust override it.
+    fn query(&self, query: Query) -> Iter<'_> {
+        let source = self.raw_mtbl_source();
+        if source.is_null() {
+            return Iter::empty();
+        }
+        Iter::new(unsafe { query.run(*source) }, source)
+    }
+
+    /// The first and last keys in this source, if they can be known without
+    /// scanning it. A [`Merger`](struct.Merger.html) uses them to skip sources
+    /// that cannot contain the keys a lookup asks for. The default is `None`,
+    /// meaning unknown, so the source is always consulted. Sources whose
+    /// members can change, like a [`Fileset`](struct.Fileset.html), return
+    /// owned copies.
+    fn key_bounds(&self) -> Option<KeyBounds<'_>> {
+        None
+    }
+
+    /// A number that increases whenever the entries of this source change
+    /// while it is shared, as when a [`Fileset`](struct.Fileset.html) reloads,
+    /// so that what is read from it can be cached until then. Sources that
+    /// can't change while shared (a `Reader`, or a `MemTable`, which needs
+    /// `&mut` to change) report a constant. The default is `None`, meaning
+    /// unknown, so nothing read from the source is cached.
+    fn content_version(&self) -> Option<u64> {
+        None
+    }
+
     /// Get the value of a key, if it's present.
     fn get<T>(&self, key: T) -> Option<Vec<u8>>
         where Self: Sized,
               T: AsRef<[u8]>
     {
-        let key = key.as_ref();
-        unsafe {
-            let mut iter = mtbl_sys::mtbl_source_get(*self.raw_mtbl_source(),
-                                                     key.as_ptr(),
-                                                     key.len());
-            let mut keyptr: *const u8 = ptr::null();
-            let mut keylen: size_t = 0;
-            let mut valptr: *const u8 = ptr::null();
-            let mut vallen: size_t = 0;
-            let res = mtbl_sys::mtbl_iter_next(iter,
-                                               &mut keyptr,
-                                               &mut keylen,
-                                               &mut valptr,
-                                               &mut vallen);
-            let retval = match res {
-                mtbl_sys::MtblRes::mtbl_res_success => {
-                    Some(slice::from_raw_parts(valptr, vallen).to_vec())
-                }
-                mtbl_sys::MtblRes::mtbl_res_failure => None,
-            };
-            mtbl_sys::mtbl_iter_destroy(&mut iter);
-            retval
+        self.query(Query::Get(key.as_ref())).next().map(|(_, value)| value)
+    }
+
+    /// Get the values of several keys, in the order the keys were given, with
+    /// `None` for keys that aren't present.
+    ///
+    /// The keys are sorted and looked up in one pass, so that a `Reader` reads
+    /// each of its data blocks at most once; for keys that are close together
+    /// this is much faster than calling `get` for each.
+    fn get_many<I, T>(&self, keys: I) -> Vec<Option<Vec<u8>>>
+        where Self: Sized,
+              I: IntoIterator<Item = T>,
+              T: AsRef<[u8]>
+    {
+        let keys: Vec<T> = keys.into_iter().collect();
+        let mut order: Vec<usize> = (0..keys.len()).collect();
+        order.sort_by(|&a, &b| keys[a].as_ref().cmp(keys[b].as_ref()));
+        let sorted: Vec<&[u8]> = order.iter().map(|&i| keys[i].as_ref()).collect();
+        let mut values = vec![None; keys.len()];
+        for (i, value) in order.into_iter().zip(self.get_sorted(&sorted)) {
+            values[i] = value;
         }
+        values
+    }
+
+    /// Get the values of keys given in sorted order (possibly with duplicates),
+    /// for [`get_many`](#method.get_many). The default looks each key up on its
+    /// own; sources that can share work between nearby keys override it.
+    fn get_sorted(&self, keys: &[&[u8]]) -> Vec<Option<Vec<u8>>> {
+        get_each(self, keys)
+    }
+
+    /// Get the entry with the smallest key, if there are any entries.
+    fn first(&self) -> Option<(Vec<u8>, Vec<u8>)> {
+        self.nearest(Nearest::First)
+    }
+
+    /// Get the entry with the largest key, if there are any entries.
+    fn last(&self) -> Option<(Vec<u8>, Vec<u8>)> {
+        self.nearest(Nearest::Last)
+    }
+
+    /// Get the entry with the largest key at or before the given key.
+    fn floor<T>(&self, key: T) -> Option<(Vec<u8>, Vec<u8>)>
+        where Self: Sized,
+              T: AsRef<[u8]>
+    {
+        self.nearest(Nearest::Floor(key.as_ref()))
+    }
+
+    /// Get the entry with the smallest key at or after the given key.
+    fn ceiling<T>(&self, key: T) -> Option<(Vec<u8>, Vec<u8>)>
+        where Self: Sized,
+              T: AsRef<[u8]>
+    {
+        self.nearest(Nearest::Ceiling(key.as_ref()))
+    }
+
+    /// Get the entry with the largest key strictly before the given key.
+    fn lower<T>(&self, key: T) -> Option<(Vec<u8>, Vec<u8>)>
+        where Self: Sized,
+              T: AsRef<[u8]>
+    {
+        self.nearest(Nearest::Lower(key.as_ref()))
+    }
+
+    /// Get the entry with the smallest key strictly after the given key.
+    fn higher<T>(&self, key: T) -> Option<(Vec<u8>, Vec<u8>)>
+        where Self: Sized,
+              T: AsRef<[u8]>
+    {
+        self.nearest(Nearest::Higher(key.as_ref()))
+    }
+
+    /// Find the entry nearest to a key, for [`first`](#method.first),
+    /// [`floor`](#method.floor) and the rest. The default scans from the
+    /// beginning; sources that can seek override it.
+    fn nearest(&self, target: Nearest) -> Option<(Vec<u8>, Vec<u8>)> {
+        scan_nearest(self.iter(), target)
     }
 
     /// Get an iterator over all keys and values.
     fn iter(&self) -> Iter {
-        let source = self.raw_mtbl_source();
-        Iter::new(unsafe { mtbl_sys::mtbl_source_iter(*source) }, source)
+        self.query(Query::All)
     }
 
     /// Get an iterator over all keys and values where the key starts with the given prefix.
@@ -62,12 +164,7 @@ pub trait Read {
         where Self: Sized,
               T: AsRef<[u8]>
     {
-        let prefix = prefix.as_ref();
-        let source = self.raw_mtbl_source();
-        Iter::new(unsafe {
-                      mtbl_sys::mtbl_source_get_prefix(*source, prefix.as_ptr(), prefix.len())
-                  },
-                  source)
+        self.query(Query::Prefix(prefix.as_ref()))
     }
 
 
@@ -78,20 +175,208 @@ pub trait Read {
               T: AsRef<[u8]>,
               U: AsRef<[u8]>
     {
-        let key0 = key0.as_ref();
-        let key1 = key1.as_ref();
-        let source = self.raw_mtbl_source();
-        Iter::new(unsafe {
-                      mtbl_sys::mtbl_source_get_range(*source,
-                                                      key0.as_ptr(),
-                                                      key0.len(),
-                                                      key1.as_ptr(),
-                                                      key1.len())
-                  },
-                  source)
+        self.query(Query::Range(key0.as_ref(), key1.as_ref()))
+    }
+}
+
+/// The first and last keys of a source, from
+/// [`Read::key_bounds`](trait.Read.html#method.key_bounds): borrowed from the
+/// source, or copied if it can change.
+pub type KeyBounds<'a> = (Cow<'a, [u8]>, Cow<'a, [u8]>);
+
+/// A lookup for [`Read::query`](trait.Read.html#method.query).
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum Query<'a> {
+    /// All entries.
+    All,
+    /// The entry for a key.
+    Get(&'a [u8]),
+    /// The entries whose keys start with a prefix.
+    Prefix(&'a [u8]),
+    /// The entries whose keys are between two keys (inclusive).
+    Range(&'a [u8], &'a [u8]),
+}
+
+impl<'a> Query<'a> {
+    /// Whether a source whose keys are all between first and last may hold
+    /// entries that the lookup asks for.
+    pub fn overlaps(&self, first: &[u8], last: &[u8]) -> bool {
+        match *self {
+            Query::All => true,
+            Query::Get(key) => first <= key && key <= last,
+            Query::Prefix(prefix) => {
+                last >= prefix && (first <= prefix || first.starts_with(prefix))
+            }
+            Query::Range(key0, key1) => first <= key1 && last >= key0,
+        }
+    }
+
+    /// Run the lookup against an MTBL source, which must not be null.
+    pub(crate) unsafe fn run(&self, source: *const mtbl_sys::mtbl_source)
+                             -> *mut mtbl_sys::mtbl_iter {
+        match *self {
+            Query::All => mtbl_sys::mtbl_source_iter(source),
+            Query::Get(key) => mtbl_sys::mtbl_source_get(source, key.as_ptr(), key.len()),
+            Query::Prefix(prefix) => {
+                mtbl_sys::mtbl_source_get_prefix(source, prefix.as_ptr(), prefix.len())
+            }
+            Query::Range(key0, key1) => {
+                mtbl_sys::mtbl_source_get_range(source,
+                                                key0.as_ptr(),
+                                                key0.len(),
+                                                key1.as_ptr(),
+                                                key1.len())
+            }
+        }
+    }
+}
+
+/// Which entry to find with [`Read::nearest`](trait.Read.html#method.nearest).
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum Nearest<'a> {
+    /// The entry with the smallest key.
+    First,
+    /// The entry with the largest key.
+    Last,
+    /// The entry with the largest key at or before this one.
+    Floor(&'a [u8]),
+    /// The entry with the smallest key at or after this one.
+    Ceiling(&'a [u8]),
+    /// The entry with the largest key strictly before this one.
+    Lower(&'a [u8]),
+    /// The entry with the smallest key strictly after this one.
+    Higher(&'a [u8]),
+}
+
+impl<'a> Nearest<'a> {
+    /// Whether the entry is looked for at or after the key, rather than before.
+    pub fn is_forward(&self) -> bool {
+        match *self {
+            Nearest::First | Nearest::Ceiling(_) | Nearest::Higher(_) => true,
+            Nearest::Last | Nearest::Floor(_) | Nearest::Lower(_) => false,
+        }
+    }
+
+    /// Whether a source with these first and last keys can't hold the entry.
+    pub fn excludes(&self, first: &[u8], last: &[u8]) -> bool {
+        match *self {
+            Nearest::First | Nearest::Last => false,
+            Nearest::Floor(key) => first > key,
+            Nearest::Lower(key) => first >= key,
+            Nearest::Ceiling(key) => last < key,
+            Nearest::Higher(key) => last <= key,
+        }
     }
 }
 
+/// A boxed `Read` (including a `Box<dyn Read>`) reads from the same source as
+/// its contents.
+impl<R: Read + ?Sized> Read for Box<R> {
+    fn raw_mtbl_source(&self) -> &*const mtbl_sys::mtbl_source {
+        (**self).raw_mtbl_source()
+    }
+    fn query(&self, query: Query) -> Iter<'_> {
+        (**self).query(query)
+    }
+    fn content_version(&self) -> Option<u64> {
+        (**self).content_version()
+    }
+    fn key_bounds(&self) -> Option<KeyBounds<'_>> {
+        (**self).key_bounds()
+    }
+    fn get_sorted(&self, keys: &[&[u8]]) -> Vec<Option<Vec<u8>>> {
+        (**self).get_sorted(keys)
+    }
+    fn nearest(&self, target: Nearest) -> Option<(Vec<u8>, Vec<u8>)> {
+        (**self).nearest(target)
+    }
+}
+
+/// A shared `Read` reads from the same source as its contents.
+impl<R: Read + ?Sized> Read for Rc<R> {
+    fn raw_mtbl_source(&self) -> &*const mtbl_sys::mtbl_source {
+        (**self).raw_mtbl_source()
+    }
+    fn query(&self, query: Query) -> Iter<'_> {
+        (**self).query(query)
+    }
+    fn content_version(&self) -> Option<u64> {
+        (**self).content_version()
+    }
+    fn key_bounds(&self) -> Option<KeyBounds<'_>> {
+        (**self).key_bounds()
+    }
+    fn get_sorted(&self, keys: &[&[u8]]) -> Vec<Option<Vec<u8>>> {
+        (**self).get_sorted(keys)
+    }
+    fn nearest(&self, target: Nearest) -> Option<(Vec<u8>, Vec<u8>)> {
+        (**self).nearest(target)
+    }
+}
+
+/// A shared `Read` reads from the same source as its contents.
+impl<R: Read + ?Sized> Read for Arc<R> {
+    fn raw_mtbl_source(&self) -> &*const mtbl_sys::mtbl_source {
+        (**self).raw_mtbl_source()
+    }
+    fn query(&self, query: Query) -> Iter<'_> {
+        (**self).query(query)
+    }
+    fn content_version(&self) -> Option<u64> {
+        (**self).content_version()
+    }
+    fn key_bounds(&self) -> Option<KeyBounds<'_>> {
+        (**self).key_bounds()
+    }
+    fn get_sorted(&self, keys: &[&[u8]]) -> Vec<Option<Vec<u8>>> {
+        (**self).get_sorted(keys)
+    }
+    fn nearest(&self, target: Nearest) -> Option<(Vec<u8>, Vec<u8>)> {
+        (**self).nearest(target)
+    }
+}
+
+/// A borrowed `Read` reads from the same source as the value it borrows.
+impl<R: Read + ?Sized> Read for &R {
+    fn raw_mtbl_source(&self) -> &*const mtbl_sys::mtbl_source {
+        (**self).raw_mtbl_source()
+    }
+    fn query(&self, query: Query) -> Iter<'_> {
+        (**self).query(query)
+    }
+    fn content_version(&self) -> Option<u64> {
+        (**self).content_version()
+    }
+    fn key_bounds(&self) -> Option<KeyBounds<'_>> {
+        (**self).key_bounds()
+    }
+    fn get_sorted(&self, keys: &[&[u8]]) -> Vec<Option<Vec<u8>>> {
+        (**self).get_sorted(keys)
+    }
+    fn nearest(&self, target: Nearest) -> Option<(Vec<u8>, Vec<u8>)> {
+        (**self).nearest(target)
+    }
+}
+
+/// Find the entry nearest to a key among entries in sorted order.
+fn scan_nearest(mut entries: Iter, target: Nearest) -> Option<(Vec<u8>, Vec<u8>)> {
+    match target {
+        Nearest::First => entries.next(),
+        Nearest::Last => entries.last(),
+        Nearest::Floor(key) => entries.take_while(|e| &e.0[..] <= key).last(),
+        Nearest::Lower(key) => entries.take_while(|e| &e.0[..] < key).last(),
+        Nearest::Ceiling(key) => entries.find(|e| &e.0[..] >= key),
+        Nearest::Higher(key) => entries.find(|e| &e.0[..] > key),
+    }
+}
+
+/// Look up each of several keys in a source on its own.
+fn get_each<R: Read + ?Sized>(source: &R, keys: &[&[u8]]) -> Vec<Option<Vec<u8>>> {
+    keys.iter()
+        .map(|key| source.query(Query::Get(key)).next().map(|(_, value)| value))
+        .collect()
+}
+
 impl<'a> IntoIterator for &'a Read {
     type Item = (Vec<u8>, Vec<u8>);
     type IntoIter = Iter<'a>;
@@ -102,22 +387,56 @@ impl<'a> IntoIterator for &'a Read {
 }
 
 /// An iterator that steps through a section of an MTBL. This is a low-level
-/// struct that interacts with the mtbl library directly.
+/// struct that interacts with the mtbl library directly, or wraps the entries
+/// of a source implemented in Rust.
 pub struct Iter<'a> {
     mtbl_iter: *mut mtbl_sys::mtbl_iter,
-    _source: &'a *const mtbl_sys::mtbl_source,
+    _source: PhantomData<&'a *const mtbl_sys::mtbl_source>,
+    // A merger made just for this iterator, destroyed along with it.
+    mtbl_merger: *mut mtbl_sys::mtbl_merger,
+    entries: Option<SourceIter<'a>>,
 }
 
 impl<'a> Iter<'a> {
     /// Create an iterator for an mtbl_source.
     pub fn new(mtbl_iter: *mut mtbl_sys::mtbl_iter,
-               source: &'a *const mtbl_sys::mtbl_source)
+               _source: &'a *const mtbl_sys::mtbl_source)
                -> Iter<'a> {
         Iter {
             mtbl_iter: mtbl_iter,
-            _source: source,
+            _source: PhantomData,
+            mtbl_merger: ptr::null_mut(),
+            entries: None,
         }
     }
+
+    /// Create an iterator over entries produced in Rust, which must be in
+    /// strictly increasing key order.
+    pub fn from_entries(entries: SourceIter<'a>) -> Iter<'a> {
+        let mut iter = Iter::empty();
+        iter.entries = Some(entries);
+        iter
+    }
+
+    /// Create an iterator that yields nothing.
+    pub fn empty() -> Iter<'a> {
+        Iter {
+            mtbl_iter: ptr::null_mut(),
+            _source: PhantomData,
+            mtbl_merger: ptr::null_mut(),
+            entries: None,
+        }
+    }
+
+    /// Create an iterator over a merger that it takes ownership of.
+    pub(crate) fn with_merger(mtbl_iter: *mut mtbl_sys::mtbl_iter,
+                              mtbl_merger: *mut mtbl_sys::mtbl_merger,
+                              source: &'a *const mtbl_sys::mtbl_source)
+                              -> Iter<'a> {
+        let mut iter = Iter::new(mtbl_iter, source);
+        iter.mtbl_merger = mtbl_merger;
+        iter
+    }
 }
 
 impl<'a> Iterator for Iter<'a> {
@@ -125,6 +444,12 @@ impl<'a> Iterator for Iter<'a> {
     type Item = (Vec<u8>, Vec<u8>);
 
     fn next(&mut self) -> Option<Self::Item> {
+        if let Some(ref mut entries) = self.entries {
+            return entries.next();
+        }
+        if self.mtbl_iter.is_null() {
+            return None;
+        }
         unsafe {
             let mut keyptr: *const u8 = ptr::null();
             let mut keylen: size_t = 0;
@@ -149,7 +474,12 @@ impl<'a> Iterator for Iter<'a> {
 impl<'a> Drop for Iter<'a> {
     fn drop(&mut self) {
         unsafe {
-            mtbl_sys::mtbl_iter_destroy(&mut self.mtbl_iter);
+            if !self.mtbl_iter.is_null() {
+                mtbl_sys::mtbl_iter_destroy(&mut self.mtbl_iter);
+            }
+            if !self.mtbl_merger.is_null() {
+                mtbl_sys::mtbl_merger_destroy(&mut self.mtbl_merger);
+            }
         }
     }
 }
@@ -195,13 +525,39 @@ impl ReaderOptions {
 
     /// Open an MTBL reader with these options from a file described by the
     /// given path.
+    ///
+    /// Only the file's metadata and index are checked when it is opened; see
+    /// [`open_from_file`](#method.open_from_file). A file that may be damaged
+    /// or comes from an untrusted source must pass
+    /// [`Reader::verify`](struct.Reader.html#method.verify) before anything is
+    /// read from it.
     pub fn open_from_path<T: AsRef<Path>>(self: &Self, path: T) -> IOResult<Reader> {
         File::open(path).and_then(|f| self.open_from_file(&f))
     }
 
     /// Open an MTBL reader with these options from a file object.
+    ///
+    /// The file's trailer and metadata, its index block, and the bounds of the
+    /// data blocks that the index lists are checked before libmtbl reads it. A
+    /// file that is missing its metadata, as when it is still being written,
+    /// gives an error of kind `UnexpectedEof`, and one whose metadata or index
+    /// is damaged gives an error of kind `InvalidData`. The contents of data
+    /// blocks aren't checked, and libmtbl trusts them when reading, so a file
+    /// that may be damaged or comes from an untrusted source must pass
+    /// [`Reader::verify`](struct.Reader.html#method.verify) before anything is
+    /// read from it.
     pub fn open_from_file<T: 'static + AsRawFd>(self: &Self, file: &T) -> IOResult<Reader> {
         let fd = file.as_raw_fd();
+        // The file is only mapped here while it is checked; after that,
+        // libmtbl's mapping is the only one.
+        let data = Mmap::map(fd)?;
+        let version = format::check_file(data.as_slice())?;
+        // Kept for mapping the file again in `blocks` and `verify`.
+        let dup = unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 0) };
+        if dup < 0 {
+            return Err(Error::last_os_error());
+        }
+        let dup = unsafe { File::from_raw_fd(dup) };
         unsafe {
             let mut mtbl_options = mtbl_sys::mtbl_reader_options_init();
             if let Some(verify_checksums) = self.verify_checksums {
@@ -213,15 +569,24 @@ impl ReaderOptions {
             let mtbl_reader = mtbl_sys::mtbl_reader_init_fd(fd, mtbl_options);
             mtbl_sys::mtbl_reader_options_destroy(&mut mtbl_options);
             if mtbl_reader.is_null() {
-                Err(Error::new(ErrorKind::Other, "failed to open MTBL file"))
-            } else {
-                Ok(Reader {
-                    options: *self,
-                    mtbl_reader: mtbl_reader,
-                    mtbl_source: mtbl_sys::mtbl_reader_source(mtbl_reader),
-                    mtbl_metadata: mtbl_sys::mtbl_reader_metadata(mtbl_reader),
-                })
+                return Err(Error::new(ErrorKind::InvalidData, "failed to open MTBL file"));
             }
+            let mtbl_metadata = mtbl_sys::mtbl_reader_metadata(mtbl_reader);
+            // `check_file` has made sure that the index block is there.
+            let index_offset = mtbl_sys::mtbl_metadata_index_block_offset(mtbl_metadata);
+            let index_block = format::raw_block(data.as_slice(), index_offset, version)
+                                  .map_or_else(Vec::new, |index| index.contents.to_vec());
+            Ok(Reader {
+                options: *self,
+                mtbl_reader: mtbl_reader,
+                mtbl_source: mtbl_sys::mtbl_reader_source(mtbl_reader),
+                mtbl_metadata,
+                file: dup,
+                file_size: data.as_slice().len() as u64,
+                index_block,
+                version,
+                key_range: OnceLock::new(),
+            })
         }
     }
 }
@@ -238,10 +603,20 @@ pub struct Reader {
     mtbl_reader: *mut mtbl_sys::mtbl_reader,
     mtbl_source: *const mtbl_sys::mtbl_source,
     mtbl_metadata: *const mtbl_sys::mtbl_metadata,
+    // libmtbl keeps its mapping of the file private, so the parts of the
+    // format it does not expose are read from a copy of the index block, or
+    // from a mapping made just for the occasion.
+    file: File,
+    file_size: u64,
+    index_block: Vec<u8>,
+    version: FormatVersion,
+    key_range: OnceLock<Option<(Vec<u8>, Vec<u8>)>>,
 }
 
 impl Reader {
-    /// Open an MTBL reader from a file described by the given path.
+    /// Open an MTBL reader from a file described by the given path. As with
+    /// [`ReaderOptions::open_from_path`](struct.ReaderOptions.html#method.open_from_path),
+    /// untrusted files must pass [`verify`](#method.verify) before being read.
     pub fn open_from_path<T: AsRef<Path>>(path: T) -> IOResult<Reader> {
         ReaderOptions::new().open_from_path(path)
     }
@@ -313,12 +688,346 @@ impl Reader {
             mtbl_sys::mtbl_metadata_bytes_values(self.mtbl_metadata)
         }
     }
+
+    /// The size of the MTBL file, in bytes, which is how much memory libmtbl
+    /// has mapped for it.
+    pub fn file_size(&self) -> u64 {
+        self.file_size
+    }
+
+    /// The first and last keys in the file, or `None` if it is empty.
+    ///
+    /// This reads the first entry and the last data block the first time it is
+    /// called, so it is cheap even for large files.
+    pub fn key_range(&self) -> Option<(Vec<u8>, Vec<u8>)> {
+        self.key_range.get_or_init(|| self.read_key_range()).clone()
+    }
+
+    fn read_key_range(&self) -> Option<(Vec<u8>, Vec<u8>)> {
+        let (first, _) = self.iter().next()?;
+        // Each index key is at least the last key of its data block and less
+        // than the first key of the next one, so the last block's entries are
+        // the ones after the second-to-last index key.
+        let mut index_keys = (None, None);
+        for (key, _) in self.index()?.iter() {
+            index_keys = (index_keys.1, Some(key));
+        }
+        let last = match index_keys {
+            (Some(start), Some(end)) => self.get_range(start, end).last(),
+            (None, Some(end)) => self.get_range(&first, end).last(),
+            _ => None,
+        };
+        last.map(|(last, _)| (first, last))
+    }
+
+    /// Keys that cut the file into `n` pieces of roughly equal size, for
+    /// dividing work over it.
+    ///
+    /// The points are in increasing order; the first piece holds the keys up to
+    /// and including the first point, and each later piece the keys after one
+    /// point up to and including the next. The points come from the index and
+    /// need not be keys in the file. Pieces are made of whole data blocks, so a
+    /// file with few blocks gives fewer than `n - 1` points.
+    pub fn split_points(&self, n: usize) -> Vec<Vec<u8>> {
+        self.block_splits(n, b"", None)
+    }
+
+    /// Like [`split_points`](#method.split_points), but for the data blocks
+    /// that may hold keys between key0 and key1 (inclusive, or to the end of
+    /// the file if key1 is `None`). The points are at least key0 and less than
+    /// key1.
+    fn block_splits(&self, n: usize, key0: &[u8], key1: Option<&[u8]>) -> Vec<Vec<u8>> {
+        let mut points = Vec::new();
+        let index = match self.index() {
+            Some(index) => index,
+            None => return points,
+        };
+        // Each data block holds the keys after the previous block's index key,
+        // up to and including its own, so the blocks in the range are the
+        // first one whose index key is at least key0 and those after it that
+        // start at or before key1.
+        let mut blocks: Vec<(Vec<u8>, u64)> = Vec::new();
+        let mut end = self.index_block_offset();
+        for IndexEntry { key: block_end, offset } in IndexEntries::new(index.iter_before(key0)) {
+            if &block_end[..] < key0 {
+                continue;
+            }
+            if let (Some(key1), Some((previous, _))) = (key1, blocks.last()) {
+                if &previous[..] >= key1 {
+                    end = offset;
+                    break;
+                }
+            }
+            blocks.push((block_end, offset));
+        }
+        let start = match blocks.first() {
+            Some(&(_, offset)) => offset,
+            None => return points,
+        };
+        let total = end.saturating_sub(start) as u128;
+        let mut piece = 1;
+        let mut blocks = blocks.into_iter().peekable();
+        while let Some((block_end, _)) = blocks.next() {
+            let next_offset = match blocks.peek() {
+                Some(&(_, offset)) => offset.saturating_sub(start) as u128,
+                // Splitting after the last block would leave an empty piece.
+                None => break,
+            };
+            // Cut after the block that crosses the end of the current piece.
+            if piece < n && next_offset * n as u128 >= total * piece as u128 {
+                while piece < n && next_offset * n as u128 >= total * piece as u128 {
+                    piece += 1;
+                }
+                points.push(block_end);
+            }
+        }
+        points
+    }
+
+    /// Get a parallel iterator over all keys and values, which scans pieces of
+    /// the file on rayon's thread pool. Collecting it keeps the entries in
+    /// order.
+    #[cfg(feature = "rayon")]
+    pub fn par_iter(&self) -> impl ParallelIterator<Item = (Vec<u8>, Vec<u8>)> + '_ {
+        let last = self.key_range().map(|(_, last)| last).unwrap_or_default();
+        self.par_range(b"", last)
+    }
+
+    /// Get a parallel iterator over all keys and values where the keys are
+    /// between key0 and key1 (inclusive), like
+    /// [`get_range`](trait.Read.html#method.get_range).
+    ///
+    /// The range is cut into pieces of whole data blocks, a few for each
+    /// thread, each scanned with its own range lookup so that no block is
+    /// decoded twice.
+    #[cfg(feature = "rayon")]
+    pub fn par_range<T, U>(&self,
+                           key0: T,
+                           key1: U)
+                           -> impl ParallelIterator<Item = (Vec<u8>, Vec<u8>)> + '_
+        where T: AsRef<[u8]>,
+              U: AsRef<[u8]>
+    {
+        let key1 = key1.as_ref();
+        let n = rayon::current_num_threads() * 4;
+        let mut pieces = Vec::new();
+        let mut start = key0.as_ref().to_vec();
+        for point in self.block_splits(n, &start, Some(key1)) {
+            // The smallest key after the point, which is in the next block, so
+            // that the lookup doesn't start in the block before it.
+            let mut next = point.clone();
+            next.push(0);
+            pieces.push((mem::replace(&mut start, next), point));
+        }
+        pieces.push((start, key1.to_vec()));
+        pieces.into_par_iter().flat_map_iter(move |(start, end)| self.get_range(start, end))
+    }
+
+    /// The approximate number of bytes taken by the entries with keys between
+    /// key0 and key1 (inclusive), computed from the sizes of the data blocks
+    /// that may hold them without reading those blocks. For compressed files,
+    /// this is the compressed size.
+    pub fn approximate_size<T, U>(&self, key0: T, key1: U) -> u64
+        where T: AsRef<[u8]>,
+              U: AsRef<[u8]>
+    {
+        let (key0, key1) = (key0.as_ref(), key1.as_ref());
+        let index = match self.index() {
+            Some(index) => index,
+            None => return 0,
+        };
+        // Each data block holds the keys after the previous block's index key,
+        // up to and including its own.
+        let mut start = None;
+        let mut end = self.index_block_offset();
+        let mut previous_end: Option<Vec<u8>> = None;
+        for IndexEntry { key: block_end, offset } in IndexEntries::new(index.iter_before(key0)) {
+            if previous_end.as_ref().is_some_and(|previous| &previous[..] >= key1) {
+                end = offset;
+                break;
+            }
+            if start.is_none() && &block_end[..] >= key0 {
+                start = Some(offset);
+            }
+            previous_end = Some(block_end);
+        }
+        start.map_or(0, |start| end.saturating_sub(start))
+    }
+
+    /// The approximate number of entries with keys between key0 and key1
+    /// (inclusive), estimated from
+    /// [`approximate_size`](#method.approximate_size) and the average size of
+    /// an entry.
+    pub fn approximate_count<T, U>(&self, key0: T, key1: U) -> u64
+        where T: AsRef<[u8]>,
+              U: AsRef<[u8]>
+    {
+        let total = self.index_block_offset() as u128;
+        if total == 0 {
+            return 0;
+        }
+        let size = self.approximate_size(key0, key1) as u128;
+        (self.count_entries() as u128 * size / total) as u64
+    }
+
+    /// Iterate over the data blocks of the file in order, describing each one,
+    /// for debugging and tooling. The file is mapped a second time while the
+    /// iterator is alive. Fails if the index block is malformed. Compressed
+    /// blocks can only be described with the `compression` feature.
+    pub fn blocks(&self) -> IOResult<Blocks<'_>> {
+        Ok(Blocks::new(self.map()?,
+                       self.version,
+                       self.compression_algorithm(),
+                       format::max_block_len(self.data_block_size()),
+                       self.index_entries()?))
+    }
+
+    /// Check the whole file for damage: the checksum and entries of every data
+    /// block and of the index, the order of all keys, and the totals in the
+    /// metadata block. Unlike
+    /// [`verify_checksums`](struct.ReaderOptions.html#method.verify_checksums),
+    /// this reads every block, mapping the file a second time while it runs,
+    /// so for large files it may be better run with
+    /// [`verify_in_background`](#method.verify_in_background). Without the
+    /// `compression` feature, each compressed data block is reported as
+    /// unreadable.
+    pub fn verify(&self) -> VerifyReport {
+        inspect::verify(self)
+    }
+
+    /// Run [`verify`](#method.verify) on a new thread.
+    pub fn verify_in_background(self: Arc<Self>) -> JoinHandle<VerifyReport> {
+        thread::spawn(move || self.verify())
+    }
+
+    /// Map the whole file, for reading the parts that libmtbl doesn't expose.
+    pub(crate) fn map(&self) -> IOResult<Mmap> {
+        Mmap::map(self.file.as_raw_fd())
+    }
+
+    /// The version of the file format.
+    pub(crate) fn format_version(&self) -> FormatVersion {
+        self.version
+    }
+
+    /// Iterate over the entries of the index block, which give the offset of
+    /// each data block. Fails if the index block is malformed.
+    pub fn index_entries(&self) -> IOResult<IndexEntries<'_>> {
+        let index = self.index().ok_or_else(|| {
+            Error::new(ErrorKind::InvalidData, "malformed index block")
+        })?;
+        Ok(IndexEntries::new(index.iter()))
+    }
+
+    /// The index block, which maps a key at or after the end of each data
+    /// block to the block's offset.
+    fn index(&self) -> Option<Block<'_>> {
+        Block::new(&self.index_block)
+    }
 }
 
 impl Read for Reader {
     fn raw_mtbl_source(&self) -> &*const mtbl_sys::mtbl_source {
         &self.mtbl_source
     }
+
+    /// A file never changes once written.
+    fn content_version(&self) -> Option<u64> {
+        Some(0)
+    }
+
+    /// The bounds from [`key_range`](#method.key_range).
+    fn key_bounds(&self) -> Option<KeyBounds<'_>> {
+        self.key_range.get_or_init(|| self.read_key_range())
+            .as_ref()
+            .map(|(first, last)| (Cow::Borrowed(&first[..]), Cow::Borrowed(&last[..])))
+    }
+
+    /// Walks the index once, reading the keys that fall in each data block
+    /// with a single range lookup.
+    fn get_sorted(&self, keys: &[&[u8]]) -> Vec<Option<Vec<u8>>> {
+        let index = match self.index() {
+            Some(index) => index,
+            None => return get_each(self, keys),
+        };
+        let mut values = Vec::with_capacity(keys.len());
+        // Each index key is at least the last key of its data block and less
+        // than the first key of the next one.
+        let mut block_ends = index.iter().map(|(key, _)| key);
+        let mut block_end = block_ends.next();
+        let mut start = 0;
+        while start < keys.len() {
+            while block_end.as_ref().is_some_and(|end| keys[start] > &end[..]) {
+                block_end = block_ends.next();
+            }
+            let end = match block_end {
+                Some(ref end) => end,
+                // Past the last block, the keys can't be present, unless the
+                // index is damaged; let libmtbl decide.
+                None => break,
+            };
+            let len = keys[start..].iter().take_while(|key| **key <= &end[..]).count();
+            let group = &keys[start..start + len];
+            let mut entries = self.get_range(group[0], group[len - 1]).peekable();
+            for key in group {
+                while entries.peek().is_some_and(|e| &e.0[..] < *key) {
+                    entries.next();
+                }
+                values.push(match entries.peek() {
+                    Some(e) if &e.0[..] == *key => Some(e.1.clone()),
+                    _ => None,
+                });
+            }
+            start += len;
+        }
+        values.extend(get_each(self, &keys[start..]));
+        values
+    }
+
+    /// Seeks with the index, so that at most two data blocks are read.
+    fn nearest(&self, target: Nearest) -> Option<(Vec<u8>, Vec<u8>)> {
+        let key = match target {
+            Nearest::First => return self.iter().next(),
+            Nearest::Last => {
+                let last = self.key_range()?.1;
+                let value = self.get(&last)?;
+                return Some((last, value));
+            }
+            Nearest::Floor(key) | Nearest::Ceiling(key) | Nearest::Lower(key) |
+            Nearest::Higher(key) => key,
+        };
+        let index = match self.index() {
+            Some(index) => index,
+            None => return scan_nearest(self.iter(), target),
+        };
+        // Each index key is at least the last key of its data block and less
+        // than the first key of the next one. Find the ends of the two blocks
+        // before the one that could hold `key`, of that block and of the next.
+        let (mut before, mut end, mut after) = ((None, None), None, None);
+        for (block_end, _) in index.iter_before(key) {
+            if &block_end[..] < key {
+                before = (before.1, Some(block_end));
+            } else if end.is_none() {
+                end = Some(block_end);
+            } else {
+                after = Some(block_end);
+                break;
+            }
+        }
+        if target.is_forward() {
+            // Past the last block, there are no keys after `key`.
+            return scan_nearest(self.get_range(key, after.or(end)?), target);
+        }
+        // Look in the block that could hold `key`, from the end of the one
+        // before it.
+        let start = before.1.clone().unwrap_or_default();
+        if let Some(found) = scan_nearest(self.get_range(&start, key), target) {
+            return Some(found);
+        }
+        // Otherwise, step back one index entry: the nearest key is the last
+        // one in the block before, which ends before `key`.
+        self.get_range(before.0.unwrap_or_default(), before.1?).last()
+    }
 }
 
 impl<'a> IntoIterator for &'a Reader {
@@ -352,11 +1061,15 @@ mod tests {
     extern crate tempfile;
     use self::tempfile::NamedTempFile;
 
+    use std::fs;
+    use std::io::ErrorKind;
     use std::sync::Arc;
     use std::thread;
 
     use reader::{CompressionType, ReaderOptions, Read, Reader};
+    use test_util::{self, numbered};
     use writer::{Write, Writer};
+    use writer::WriterOptions;
 
     // Create a test MTBL file.
     fn create_mtbl(t: NamedTempFile) {
@@ -469,4 +1182,242 @@ mod tests {
         assert_eq!(3, reader.bytes_keys());
         assert_eq!(5, reader.bytes_values());
     }
+
+    #[test]
+    fn test_key_range() {
+        let tempfile_writer = NamedTempFile::new().unwrap();
+        let tempfile_reader = tempfile_writer.reopen().unwrap();
+        {
+            // Small blocks, so that the keys span several of them.
+            let mut writer = WriterOptions::new()
+                                 .block_size(64)
+                                 .create_from_file(tempfile_writer)
+                                 .unwrap();
+            for i in 0..1000 {
+                writer.add(format!("key{:04}", i), "value").unwrap();
+            }
+        }
+        let reader = Reader::open_from_file(&tempfile_reader).unwrap();
+        assert!(reader.count_data_blocks() > 2);
+        assert_eq!(reader.key_range(),
+                   Some(("key0000".as_bytes().to_vec(), "key0999".as_bytes().to_vec())));
+
+        let empty_writer = NamedTempFile::new().unwrap();
+        let empty_reader = empty_writer.reopen().unwrap();
+        drop(Writer::create_from_file(empty_writer).unwrap());
+        let reader = Reader::open_from_file(&empty_reader).unwrap();
+        assert_eq!(reader.key_range(), None);
+    }
+
+    #[test]
+    fn test_get_many() {
+        let tempfile_writer = NamedTempFile::new().unwrap();
+        let tempfile_reader = tempfile_writer.reopen().unwrap();
+        {
+            let mut writer = WriterOptions::new()
+                                 .block_size(64)
+                                 .create_from_file(tempfile_writer)
+                                 .unwrap();
+            for i in (0..1000).filter(|i| i % 3 != 0) {
+                writer.add(format!("key{:04}", i), format!("value{}", i)).unwrap();
+            }
+        }
+        let reader = Reader::open_from_file(&tempfile_reader).unwrap();
+        // Unsorted, with duplicates and keys outside the file's range.
+        let mut keys: Vec<String> = (0..1000).rev().map(|i| format!("key{:04}", i * 7 % 1000))
+                                             .collect();
+        keys.extend(vec!["a".to_string(), "key0005".to_string(), "key".to_string(),
+                         "key0999x".to_string(), "z".to_string()]);
+        let expected: Vec<_> = keys.iter().map(|k| reader.get(k)).collect();
+        assert_eq!(expected.iter().filter(|v| v.is_some()).count(), 667);
+        assert_eq!(reader.get_many(&keys), expected);
+        assert_eq!((&reader as &dyn Read).get_sorted(&[b"key0001", b"key0001"]),
+                   vec![Some(b"value1".to_vec()), Some(b"value1".to_vec())]);
+        assert_eq!(reader.get_many(Vec::<&str>::new()), Vec::<Option<Vec<u8>>>::new());
+    }
+
+    #[test]
+    fn test_nearest() {
+        let tempfile_writer = NamedTempFile::new().unwrap();
+        let tempfile_reader = tempfile_writer.reopen().unwrap();
+        {
+            let mut writer = WriterOptions::new()
+                                 .block_size(64)
+                                 .create_from_file(tempfile_writer)
+                                 .unwrap();
+            for i in (0..1000).filter(|i| i % 3 != 0) {
+                writer.add(format!("key{:04}", i), format!("value{}", i)).unwrap();
+            }
+        }
+        let reader = Reader::open_from_file(&tempfile_reader).unwrap();
+        let entries: Vec<_> = reader.iter().collect();
+        assert_eq!(reader.first(), entries.first().cloned());
+        assert_eq!(reader.last(), entries.last().cloned());
+        let mut keys: Vec<Vec<u8>> = (0..1001).map(|i| format!("key{:04}", i).into_bytes())
+                                              .collect();
+        for key in &["a", "key", "key0005x", "key0999x", "z"] {
+            keys.push(key.as_bytes().to_vec());
+        }
+        // Index keys, and the keys just after them, are at the edges of blocks.
+        for entry in reader.index_entries().unwrap() {
+            let mut after = entry.key.clone();
+            after.push(0);
+            keys.push(entry.key);
+            keys.push(after);
+        }
+        for key in &keys {
+            let key = &key[..];
+            let floor = entries.iter().rev().find(|e| &e.0[..] <= key).cloned();
+            let lower = entries.iter().rev().find(|e| &e.0[..] < key).cloned();
+            let ceiling = entries.iter().find(|e| &e.0[..] >= key).cloned();
+            let higher = entries.iter().find(|e| &e.0[..] > key).cloned();
+            assert_eq!(reader.floor(key), floor);
+            assert_eq!(reader.lower(key), lower);
+            assert_eq!(reader.ceiling(key), ceiling);
+            assert_eq!(reader.higher(key), higher);
+        }
+    }
+
+    #[test]
+    fn test_split_points() {
+        let tempfile_writer = NamedTempFile::new().unwrap();
+        let tempfile_reader = tempfile_writer.reopen().unwrap();
+        test_util::create_mtbl(tempfile_writer.path(),
+                    WriterOptions::new().block_size(64),
+                    &numbered(1000));
+        let reader = Reader::open_from_file(&tempfile_reader).unwrap();
+        assert!(reader.split_points(0).is_empty());
+        assert!(reader.split_points(1).is_empty());
+        let points = reader.split_points(4);
+        assert_eq!(points.len(), 3);
+        let mut start = Vec::new();
+        for end in points.iter().chain(Some(&b"z".to_vec())) {
+            assert!(end > &start);
+            let count = reader.get_range(&start, end).filter(|e| e.0 > start).count();
+            assert!(count > 200 && count < 300, "{} entries", count);
+            start = end.clone();
+        }
+        assert_eq!(reader.split_points(100000).len() as u64, reader.count_data_blocks() - 1);
+
+        let total = reader.index_block_offset();
+        assert_eq!(reader.approximate_size("", "z"), total);
+        assert_eq!(reader.approximate_count("", "z"), 1000);
+        assert_eq!(reader.approximate_size("z", "zz"), 0);
+        assert_eq!(reader.approximate_size("key0500", "key0400"), 0);
+        let size = reader.approximate_size("key0100", "key0349");
+        assert!(size > total / 5 && size < total / 3, "{} of {} bytes", size, total);
+        let count = reader.approximate_count("key0100", "key0349");
+        assert!(count > 200 && count < 300, "{} entries", count);
+        let one = reader.approximate_size("key0500", "key0500");
+        assert!(one > 0 && one < 2 * reader.data_block_size());
+    }
+
+    #[test]
+    fn test_corrupt_files() {
+        let mut compressions = vec![CompressionType::MTBL_COMPRESSION_NONE];
+        if cfg!(feature = "compression") {
+            compressions.extend(vec![CompressionType::MTBL_COMPRESSION_SNAPPY,
+                                     CompressionType::MTBL_COMPRESSION_ZLIB,
+                                     CompressionType::MTBL_COMPRESSION_LZ4]);
+        }
+        for compression in compressions {
+            check_corrupt_files(compression);
+        }
+    }
+
+    fn check_corrupt_files(compression: CompressionType) {
+        let tempfile_writer = NamedTempFile::new().unwrap();
+        let path = tempfile_writer.path().to_path_buf();
+        let entries: Vec<(Vec<u8>, Vec<u8>)> =
+            numbered(200).into_iter().map(|(k, v)| (k.into_bytes(), v.into_bytes())).collect();
+        test_util::create_mtbl(&path,
+                               WriterOptions::new().block_size(64).compression(compression),
+                               &entries);
+        let original = fs::read(&path).unwrap();
+        let reader = Reader::open_from_path(&path).unwrap();
+        let index_offset = reader.index_block_offset() as usize;
+        let block_offsets: Vec<u64> =
+            reader.index_entries().unwrap().map(|entry| entry.offset).collect();
+        let metadata_offset = original.len() - 512;
+        let mutated = NamedTempFile::new().unwrap();
+        let open = |data: &[u8]| {
+            fs::write(mutated.path(), data).unwrap();
+            Reader::open_from_path(mutated.path())
+        };
+
+        // Files cut short at any point are missing their metadata.
+        let lengths = (0..original.len()).step_by(37).chain(vec![511, 512, original.len() - 1]);
+        for len in lengths {
+            let err = open(&original[..len]).err().unwrap();
+            assert_eq!(err.kind(), ErrorKind::UnexpectedEof, "{} bytes", len);
+            assert!(err.to_string().contains("file truncated or still being written"));
+        }
+        let not_mtbl: Vec<u8> = (0..4096).map(|i| (i * 7) as u8).collect();
+        assert!(open(&not_mtbl).is_err());
+
+        // Flip each byte in turn. Damage to the index, or to where a data block
+        // says it ends, is caught when opening, and any other damage to a data
+        // block is reported at that block by verify, so that the file is never
+        // read. Some metadata fields aren't used at all, but a file with
+        // damaged metadata must still open safely or not at all, and read the
+        // same through libmtbl if it passes verify.
+        for pos in 0..original.len() {
+            let mut data = original.clone();
+            data[pos] ^= 0xff;
+            match open(&data) {
+                Ok(reader) => {
+                    assert!(pos < index_offset || pos >= metadata_offset, "byte {}", pos);
+                    let report = reader.verify();
+                    if pos < index_offset {
+                        let block = block_offsets.iter().rev().find(|&&o| o as usize <= pos);
+                        assert!(report.problems.iter().any(|p| Some(&p.offset) == block),
+                                "byte {}: {:?}",
+                                pos,
+                                report.problems);
+                    } else if report.is_ok() {
+                        assert_eq!(reader.iter().collect::<Vec<_>>(), entries, "byte {}", pos);
+                        assert_eq!(reader.get("key0123"),
+                                   Some(b"value123".to_vec()),
+                                   "byte {}",
+                                   pos);
+                        assert_eq!(reader.get("key0123x"), None, "byte {}", pos);
+                        assert_eq!(reader.get_range("key0050", "key0149").collect::<Vec<_>>(),
+                                   &entries[50..150],
+                                   "byte {}",
+                                   pos);
+                    }
+                }
+                Err(err) => {
+                    // Only the magic number marks the end of a complete file.
+                    let kind = if pos >= original.len() - 4 {
+                        ErrorKind::UnexpectedEof
+                    } else {
+                        ErrorKind::InvalidData
+                    };
+                    assert_eq!(err.kind(), kind, "byte {}", pos);
+                }
+            }
+        }
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn test_par_iter() {
+        use rayon::prelude::*;
+
+        let tempfile_writer = NamedTempFile::new().unwrap();
+        let tempfile_reader = tempfile_writer.reopen().unwrap();
+        test_util::create_mtbl(tempfile_writer.path(),
+                    WriterOptions::new().block_size(64),
+                    &numbered(1000));
+        let reader = Reader::open_from_file(&tempfile_reader).unwrap();
+        let entries: Vec<_> = reader.par_iter().collect();
+        assert_eq!(entries, reader.iter().collect::<Vec<_>>());
+        for &(key0, key1) in &[("key0100", "key0899"), ("key0500", "key0500"), ("a", "key0010"),
+                               ("key0990", "z"), ("key0500", "key0400"), ("z", "zz")] {
+            let entries: Vec<_> = reader.par_range(key0, key1).collect();
+            assert_eq!(entries, reader.get_range(key0, key1).collect::<Vec<_>>());
+        }
+        assert_eq!(reader.par_range("key0100", "key0199").count(), 100);
+    }
 }
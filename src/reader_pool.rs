@@ -0,0 +1,322 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::Result as IOResult;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use reader::{Reader, ReaderOptions};
+
+/// The default limit on the number of open `Reader`s.
+const DEFAULT_MAX_OPEN: usize = 1000;
+
+/// Options for creating a `ReaderPool`.
+///
+/// # Examples
+///
+/// ```
+/// let pool = ReaderPoolOptions::new().max_open(500).max_mapped_bytes(1 << 30).create();
+/// ```
+#[derive(Clone,Copy)]
+pub struct ReaderPoolOptions {
+    /// The most `Reader`s to keep open. Default is 1000.
+    pub max_open: Option<usize>,
+    /// The most bytes of MTBL files to keep mapped. Default is no limit.
+    pub max_mapped_bytes: Option<u64>,
+    /// The options used to open files. Default is the `Reader` defaults.
+    pub reader_options: Option<ReaderOptions>,
+}
+
+impl ReaderPoolOptions {
+    /// Create a `ReaderPoolOptions` with only defaults.
+    pub fn new() -> ReaderPoolOptions {
+        ReaderPoolOptions {
+            max_open: None,
+            max_mapped_bytes: None,
+            reader_options: None,
+        }
+    }
+
+    /// Create a modified `ReaderPoolOptions` with max_open set.
+    pub fn max_open(self: &Self, max_open: usize) -> ReaderPoolOptions {
+        ReaderPoolOptions { max_open: Some(max_open), ..*self }
+    }
+
+    /// Create a modified `ReaderPoolOptions` with max_mapped_bytes set.
+    pub fn max_mapped_bytes(self: &Self, max_mapped_bytes: u64) -> ReaderPoolOptions {
+        ReaderPoolOptions { max_mapped_bytes: Some(max_mapped_bytes), ..*self }
+    }
+
+    /// Create a modified `ReaderPoolOptions` with reader_options set.
+    pub fn reader_options(self: &Self, reader_options: ReaderOptions) -> ReaderPoolOptions {
+        ReaderPoolOptions { reader_options: Some(reader_options), ..*self }
+    }
+
+    /// Create an empty `ReaderPool` with these options.
+    pub fn create(self: &Self) -> ReaderPool {
+        ReaderPool {
+            options: *self,
+            state: Mutex::new(PoolState {
+                entries: HashMap::new(),
+                lru: BTreeMap::new(),
+                tick: 0,
+                mapped_bytes: 0,
+                stats: ReaderPoolStats::default(),
+            }),
+        }
+    }
+}
+
+/// Counters describing how a `ReaderPool` has been used.
+#[derive(Clone,Copy,Debug,Default,PartialEq)]
+pub struct ReaderPoolStats {
+    /// Lookups that found the `Reader` already open.
+    pub hits: u64,
+    /// Lookups that had to open the file.
+    pub misses: u64,
+    /// `Reader`s closed to stay within the limits.
+    pub evictions: u64,
+    /// The number of `Reader`s open in the pool now.
+    pub open: usize,
+    /// The total size of the files open in the pool now.
+    pub mapped_bytes: u64,
+}
+
+/// A cache of open `Reader`s keyed by path, for serving more MTBL files than
+/// can be kept open at once.
+///
+/// [`get`](#method.get) opens a file the first time it is asked for and
+/// shares the `Reader` with later callers. When the pool holds more `Reader`s
+/// or maps more bytes than its limits allow, the least recently used are
+/// dropped from it. A `Reader` that a caller still holds stays open until the
+/// caller drops it, so the limits can be exceeded by as many `Reader`s as are
+/// in use.
+///
+/// The pool assumes that files aren't changed in place; to serve a new
+/// version of a file, [`evict`](#method.evict) the old one.
+///
+/// # Examples
+///
+/// ```
+/// let pool = ReaderPool::new();
+/// let value = pool.get("/srv/tables/0001.mtbl")?.get("key");
+/// println!("{:?}", pool.stats());
+/// ```
+pub struct ReaderPool {
+    /// The options used to create this `ReaderPool`.
+    pub options: ReaderPoolOptions,
+    state: Mutex<PoolState>,
+}
+
+struct PoolState {
+    entries: HashMap<PathBuf, PoolEntry>,
+    /// The paths in `entries` by when they were last used, oldest first.
+    lru: BTreeMap<u64, PathBuf>,
+    tick: u64,
+    mapped_bytes: u64,
+    stats: ReaderPoolStats,
+}
+
+struct PoolEntry {
+    reader: Arc<Reader>,
+    last_used: u64,
+}
+
+impl PoolState {
+    /// Mark an entry as the most recently used one.
+    fn touch(&mut self, path: &Path) -> Option<Arc<Reader>> {
+        let entry = self.entries.get_mut(path)?;
+        self.lru.remove(&entry.last_used);
+        self.tick += 1;
+        entry.last_used = self.tick;
+        self.lru.insert(self.tick, path.to_path_buf());
+        Some(entry.reader.clone())
+    }
+
+    fn remove(&mut self, path: &Path) -> Option<Arc<Reader>> {
+        let entry = self.entries.remove(path)?;
+        self.lru.remove(&entry.last_used);
+        self.mapped_bytes -= entry.reader.file_size();
+        Some(entry.reader)
+    }
+
+    /// Drop least recently used entries until the limits are met, keeping at
+    /// least the most recent one.
+    fn evict(&mut self, max_open: usize, max_mapped_bytes: u64) {
+        while self.entries.len() > 1 &&
+              (self.entries.len() > max_open || self.mapped_bytes > max_mapped_bytes) {
+            let oldest = self.lru.values().next().unwrap().clone();
+            self.remove(&oldest);
+            self.stats.evictions += 1;
+        }
+    }
+}
+
+impl ReaderPool {
+    /// Create an empty `ReaderPool` with the default options.
+    pub fn new() -> ReaderPool {
+        ReaderPoolOptions::new().create()
+    }
+
+    /// Get a `Reader` for the MTBL file at a path, opening it if it isn't open
+    /// in the pool.
+    ///
+    /// Paths are compared as given, so the same file reached by two different
+    /// paths is opened twice.
+    pub fn get<T: AsRef<Path>>(&self, path: T) -> IOResult<Arc<Reader>> {
+        let path = path.as_ref();
+        {
+            let mut state = self.state.lock().unwrap();
+            if let Some(reader) = state.touch(path) {
+                state.stats.hits += 1;
+                return Ok(reader);
+            }
+            state.stats.misses += 1;
+        }
+        // Open the file without holding the lock, so that hits aren't held up.
+        let reader = Arc::new(self.options
+                                  .reader_options
+                                  .unwrap_or_else(ReaderOptions::new)
+                                  .open_from_path(path)?);
+        let mut state = self.state.lock().unwrap();
+        if let Some(reader) = state.touch(path) {
+            // Another thread opened it first.
+            return Ok(reader);
+        }
+        state.mapped_bytes += reader.file_size();
+        state.entries.insert(path.to_path_buf(),
+                             PoolEntry {
+                                 reader: reader.clone(),
+                                 last_used: 0,
+                             });
+        state.touch(path);
+        state.evict(self.options.max_open.unwrap_or(DEFAULT_MAX_OPEN),
+                    self.options.max_mapped_bytes.unwrap_or(u64::MAX));
+        Ok(reader)
+    }
+
+    /// Whether the file at a path is open in the pool. This doesn't count as a
+    /// use of it.
+    pub fn contains<T: AsRef<Path>>(&self, path: T) -> bool {
+        self.state.lock().unwrap().entries.contains_key(path.as_ref())
+    }
+
+    /// Drop the `Reader` for a path from the pool, returning whether it was
+    /// open. The next `get` opens the file again.
+    pub fn evict<T: AsRef<Path>>(&self, path: T) -> bool {
+        self.state.lock().unwrap().remove(path.as_ref()).is_some()
+    }
+
+    /// Drop every `Reader` from the pool.
+    pub fn clear(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.entries.clear();
+        state.lru.clear();
+        state.mapped_bytes = 0;
+    }
+
+    /// How the pool has been used so far.
+    pub fn stats(&self) -> ReaderPoolStats {
+        let state = self.state.lock().unwrap();
+        ReaderPoolStats {
+            open: state.entries.len(),
+            mapped_bytes: state.mapped_bytes,
+            ..state.stats
+        }
+    }
+}
+
+impl Default for ReaderPool {
+    fn default() -> ReaderPool {
+        ReaderPool::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use std::thread;
+
+    use reader::Read;
+    use reader_pool::{ReaderPool, ReaderPoolOptions, ReaderPoolStats};
+    use test_util::TempDir;
+    use writer::{Write, Writer};
+
+    fn create_tables(dir: &TempDir, n: usize) -> Vec<PathBuf> {
+        (0..n)
+            .map(|i| {
+                let path = dir.path().join(format!("{}.mtbl", i));
+                let mut writer = Writer::create_from_path(&path).unwrap();
+                writer.add("table", format!("{}", i)).unwrap();
+                path
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_lru() {
+        let dir = TempDir::new();
+        let paths = create_tables(&dir, 5);
+        let pool = ReaderPoolOptions::new().max_open(3).create();
+        for path in &paths[..3] {
+            pool.get(path).unwrap();
+        }
+        // Using 0 makes 1 the least recently used.
+        assert_eq!(pool.get(&paths[0]).unwrap().get("table"), Some(b"0".to_vec()));
+        let held = pool.get(&paths[3]).unwrap();
+        assert!(pool.contains(&paths[0]));
+        assert!(!pool.contains(&paths[1]));
+        assert!(pool.contains(&paths[2]));
+        pool.get(&paths[4]).unwrap();
+        assert!(!pool.contains(&paths[2]));
+        // An evicted reader that is still held keeps working.
+        assert!(pool.evict(&paths[3]));
+        assert!(!pool.evict(&paths[3]));
+        assert_eq!(held.get("table"), Some(b"3".to_vec()));
+
+        let stats = pool.stats();
+        assert_eq!(stats,
+                   ReaderPoolStats {
+                       hits: 1,
+                       misses: 5,
+                       evictions: 2,
+                       open: 2,
+                       mapped_bytes: stats.mapped_bytes,
+                   });
+        let size = fs::metadata(&paths[0]).unwrap().len();
+        assert_eq!(stats.mapped_bytes, 2 * size);
+        assert!(ReaderPool::new().get(dir.path().join("missing.mtbl")).is_err());
+        pool.clear();
+        assert_eq!(pool.stats().open, 0);
+        assert_eq!(pool.stats().mapped_bytes, 0);
+    }
+
+    #[test]
+    fn test_max_mapped_bytes() {
+        let dir = TempDir::new();
+        let paths = create_tables(&dir, 10);
+        let size = fs::metadata(&paths[0]).unwrap().len();
+        let pool = Arc::new(ReaderPoolOptions::new().max_mapped_bytes(4 * size).create());
+        let mut threads = Vec::new();
+        for t in 0..4 {
+            let pool = pool.clone();
+            let paths = paths.clone();
+            threads.push(thread::spawn(move || {
+                for (i, path) in paths.iter().enumerate().skip(t) {
+                    let value = pool.get(path).unwrap().get("table");
+                    assert_eq!(value, Some(format!("{}", i).into_bytes()));
+                }
+            }));
+        }
+        for t in threads {
+            t.join().unwrap();
+        }
+        let stats = pool.stats();
+        assert!(stats.open <= 4);
+        assert!(stats.mapped_bytes <= 4 * size);
+        assert_eq!(stats.hits + stats.misses, 34);
+        // Threads that open the same file at once both count a miss.
+        assert!(stats.misses - stats.evictions >= stats.open as u64);
+    }
+}
@@ -47,7 +47,7 @@ impl SorterOptions {
         unsafe {
             let mut opts = mtbl_sys::mtbl_sorter_options_init();
             mtbl_sys::mtbl_sorter_options_set_merge_func(opts,
-                                                         merger::_merge_cb_shim,
+                                                         merger::_merge_cb_shim::<merger::MergeFn>,
                                                          // Wacky casting to get a void pointer for
                                                          // the C lib.
                                                          &mut (*merge_fn) as *mut _ as *mut c_void);
@@ -0,0 +1,222 @@
+use std::ptr;
+
+use mtbl_sys;
+use reader::{Iter, Query, Read};
+
+/// An iterator over key-value pairs produced by a [`SourceImpl`](trait.SourceImpl.html).
+pub type SourceIter<'a> = Box<dyn Iterator<Item = (Vec<u8>, Vec<u8>)> + 'a>;
+
+/// A trait for Rust types that can act as an MTBL source.
+///
+/// Wrapping an implementation in a [`CustomSource`](struct.CustomSource.html)
+/// makes it readable through the [`Read`](trait.Read.html) trait, so it can be
+/// merged with on-disk tables by a [`Merger`](struct.Merger.html).
+///
+/// It never becomes an `mtbl_source` that libmtbl itself reads: libmtbl's
+/// public API has no way to build one from callbacks (`mtbl_source_init` is
+/// internal to the library). So a custom source can't be listed in a
+/// [`Fileset`](struct.Fileset.html), which only serves MTBL files, but it can
+/// be merged with a `Fileset` by a `Merger`.
+///
+/// Every iterator must yield entries in strictly increasing key order, as an
+/// MTBL file would. Only `iter` is required; the other methods have default
+/// implementations that scan `iter`, which implementations backed by an index
+/// (such as a `BTreeMap`) should override.
+pub trait SourceImpl {
+    /// Get an iterator over all keys and values.
+    fn iter(&self) -> SourceIter<'_>;
+
+    /// Get an iterator over the entry for a key (at most one entry).
+    fn get(&self, key: &[u8]) -> SourceIter<'_> {
+        self.get_range(key, key)
+    }
+
+    /// Get an iterator over all keys and values where the key starts with the given prefix.
+    fn get_prefix(&self, prefix: &[u8]) -> SourceIter<'_> {
+        let prefix = prefix.to_vec();
+        let start = prefix.clone();
+        Box::new(self.iter()
+                     .skip_while(move |e| e.0 < start)
+                     .take_while(move |e| e.0.starts_with(&prefix)))
+    }
+
+    /// Get an iterator over all keys and values, where the keys are between key0 and key1
+    /// (inclusive).
+    fn get_range(&self, key0: &[u8], key1: &[u8]) -> SourceIter<'_> {
+        let key0 = key0.to_vec();
+        let key1 = key1.to_vec();
+        Box::new(self.iter()
+                     .skip_while(move |e| e.0 < key0)
+                     .take_while(move |e| e.0 <= key1))
+    }
+}
+
+/// A [`Read`](trait.Read.html) implemented in Rust by a
+/// [`SourceImpl`](trait.SourceImpl.html).
+///
+/// A `CustomSource` has no libmtbl source of its own (its
+/// [`raw_mtbl_source`](trait.Read.html#tymethod.raw_mtbl_source) is null), so
+/// a [`Merger`](struct.Merger.html) that includes one merges in Rust rather
+/// than in libmtbl, and passes it lookups through
+/// [`Read::query`](trait.Read.html#method.query).
+///
+/// # Examples
+///
+/// ```
+/// struct MapSource(BTreeMap<Vec<u8>, Vec<u8>>);
+///
+/// impl SourceImpl for MapSource {
+///     fn iter(&self) -> SourceIter<'_> {
+///         Box::new(self.0.iter().map(|(k, v)| (k.clone(), v.clone())))
+///     }
+/// }
+///
+/// let overlay = CustomSource::new(MapSource(map));
+/// let sources: Vec<Box<dyn Read>> = vec![Box::new(reader), Box::new(overlay)];
+/// let merger = Merger::new(sources, Merger::merge_choose_last_value);
+/// ```
+pub struct CustomSource<T: SourceImpl> {
+    inner: T,
+}
+
+/// The `raw_mtbl_source` of sources implemented in Rust.
+pub(crate) const NO_MTBL_SOURCE: *const mtbl_sys::mtbl_source = ptr::null();
+
+impl<T: SourceImpl> CustomSource<T> {
+    /// Make a `SourceImpl` readable as a source.
+    pub fn new(inner: T) -> CustomSource<T> {
+        CustomSource { inner }
+    }
+
+    /// Get a reference to the wrapped `SourceImpl`.
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+
+    /// Get a mutable reference to the wrapped `SourceImpl`.
+    pub fn inner_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+}
+
+impl<T: SourceImpl> Read for CustomSource<T> {
+    fn raw_mtbl_source(&self) -> &*const mtbl_sys::mtbl_source {
+        &NO_MTBL_SOURCE
+    }
+
+    fn query(&self, query: Query) -> Iter<'_> {
+        Iter::from_entries(match query {
+            Query::All => self.inner.iter(),
+            Query::Get(key) => self.inner.get(key),
+            Query::Prefix(prefix) => self.inner.get_prefix(prefix),
+            Query::Range(key0, key1) => self.inner.get_range(key0, key1),
+        })
+    }
+}
+
+impl<'a, T: SourceImpl> IntoIterator for &'a CustomSource<T> {
+    type Item = (Vec<u8>, Vec<u8>);
+    type IntoIter = Iter<'a>;
+
+    fn into_iter(self) -> Iter<'a> {
+        self.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempfile;
+    use self::tempfile::NamedTempFile;
+
+    use std::collections::BTreeMap;
+    use std::collections::Bound::Included;
+
+    use merger::Merger;
+    use reader::{Read, Reader};
+    use source::{CustomSource, SourceImpl, SourceIter};
+    use writer::{Write, Writer};
+
+    struct MapSource(BTreeMap<Vec<u8>, Vec<u8>>);
+
+    impl SourceImpl for MapSource {
+        fn iter(&self) -> SourceIter<'_> {
+            Box::new(self.0.iter().map(|(k, v)| (k.clone(), v.clone())))
+        }
+
+        fn get_range(&self, key0: &[u8], key1: &[u8]) -> SourceIter<'_> {
+            if key0 > key1 {
+                return Box::new(None.into_iter());
+            }
+            Box::new(self.0
+                         .range::<[u8], _>((Included(key0), Included(key1)))
+                         .map(|(k, v)| (k.clone(), v.clone())))
+        }
+    }
+
+    /// A source that relies on the default scanning implementations.
+    struct VecSource(Vec<(Vec<u8>, Vec<u8>)>);
+
+    impl SourceImpl for VecSource {
+        fn iter(&self) -> SourceIter<'_> {
+            Box::new(self.0.iter().cloned())
+        }
+    }
+
+    fn entries() -> Vec<(Vec<u8>, Vec<u8>)> {
+        vec![("one", "Hello"), ("three", "green"), ("two", "world")]
+            .into_iter()
+            .map(|(k, v)| (k.as_bytes().to_vec(), v.as_bytes().to_vec()))
+            .collect()
+    }
+
+    fn check_source<T: Read>(source: &T) {
+        assert_eq!(source.get("a"), None);
+        assert_eq!(source.get("one"), Some("Hello".as_bytes().to_vec()));
+        assert_eq!(source.iter().collect::<Vec<_>>(), entries());
+        let mut it = source.get_prefix("t");
+        assert_eq!(it.next().unwrap().0, "three".as_bytes());
+        assert_eq!(it.next().unwrap().0, "two".as_bytes());
+        assert_eq!(it.next(), None);
+        let mut it = source.get_range("o", "three");
+        assert_eq!(it.next().unwrap().0, "one".as_bytes());
+        assert_eq!(it.next().unwrap().0, "three".as_bytes());
+        assert_eq!(it.next(), None);
+    }
+
+    #[test]
+    fn test_map_source() {
+        let source = CustomSource::new(MapSource(entries().into_iter().collect()));
+        check_source(&source);
+        assert_eq!(source.inner().0.len(), 3);
+    }
+
+    #[test]
+    fn test_default_methods() {
+        let source = CustomSource::new(VecSource(entries()));
+        check_source(&source);
+    }
+
+    #[test]
+    fn test_merge_with_reader() {
+        let tempfile_writer = NamedTempFile::new().unwrap();
+        let tempfile_reader = tempfile_writer.reopen().unwrap();
+        {
+            let mut writer = Writer::create_from_file(tempfile_writer).unwrap();
+            writer.add("four", "blue").unwrap();
+            writer.add("one", "old").unwrap();
+        }
+        let reader = Reader::open_from_file(&tempfile_reader).unwrap();
+        let mut overlay = CustomSource::new(MapSource(BTreeMap::new()));
+        overlay.inner_mut().0.extend(entries());
+        let sources: Vec<Box<dyn Read>> = vec![Box::new(reader), Box::new(overlay)];
+        let merger = Merger::new(sources, Merger::merge_choose_last_value);
+        assert_eq!(merger.get("one"), Some("Hello".as_bytes().to_vec()));
+        assert_eq!(merger.get("four"), Some("blue".as_bytes().to_vec()));
+        let keys: Vec<Vec<u8>> = merger.iter().map(|(k, _)| k).collect();
+        assert_eq!(keys,
+                   vec!["four".as_bytes().to_vec(),
+                        "one".as_bytes().to_vec(),
+                        "three".as_bytes().to_vec(),
+                        "two".as_bytes().to_vec()]);
+    }
+}
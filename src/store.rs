@@ -0,0 +1,651 @@
+//! An updatable key-value store built from MTBL files (enabled by the `store`
+//! feature).
+//!
+//! MTBL files can't be changed once written, so a [`Store`](struct.Store.html)
+//! layers them in the usual log-structured way:
+//!
+//! * Every write is appended to a write-ahead log in the store's directory and
+//!   added to an in-memory table, so it is visible to reads right away and
+//!   survives a crash.
+//! * When the in-memory table grows past a size limit, it is written out as a
+//!   new MTBL file with a `Writer`, the file is added to the store's `Fileset`,
+//!   and the log is started afresh.
+//! * Entries are [tombstone](../tombstone/index.html)-encoded, so deletes in
+//!   newer files hide values in older ones, and reads merge the in-memory
+//!   table with the files.
+//! * A [`Compactor`](../struct.Compactor.html) merges similarly sized files in
+//!   the background, so reads don't have to consult more and more files.
+//!
+//! A store's directory can be used by only one `Store` at a time; opening it
+//! again, from this or another process, fails until the first is dropped.
+//!
+//! # Examples
+//!
+//! ```
+//! use mtbl::store::Store;
+//!
+//! let store = Store::open("/srv/my-store")?;
+//! store.put("one", "Hello")?;
+//! store.delete("two")?;
+//! assert_eq!(store.get("one"), Some("Hello".as_bytes().to_vec()));
+//! for (key, value) in store.scan("a", "z") {
+//!     println!("{:?} = {:?}", key, value);
+//! }
+//! ```
+
+use libc;
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::Read as IORead;
+use std::io::Result as IOResult;
+use std::io::Write as IOWrite;
+use std::io::{Error, ErrorKind};
+use std::mem;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use compactor::{Compactor, CompactorOptions, CompactorThread};
+use fileset::Fileset;
+use memtable::MemTable;
+use merger::Merger;
+use reader::Read;
+use tombstone::{self, Tombstoned};
+use writer::{Write, WriterOptions};
+
+/// The default size of the in-memory table before it is flushed: 64 MiB.
+const DEFAULT_MEMTABLE_BYTES: usize = 64 << 20;
+
+/// The default interval between background compactions.
+const DEFAULT_COMPACTION_INTERVAL_SECONDS: u32 = 60;
+
+/// How many entries a `Scan` reads at a time.
+const SCAN_BATCH: usize = 256;
+
+const SETFILE_NAME: &str = "store.mtbl-fileset";
+const LOG_NAME: &str = "store.log";
+const LOCK_NAME: &str = "LOCK";
+
+/// Options for opening a `Store`.
+///
+/// # Examples
+///
+/// ```
+/// let store = StoreOptions::new().sync(true).memtable_bytes(1 << 20).open("/srv/my-store")?;
+/// ```
+#[derive(Clone,Copy)]
+pub struct StoreOptions {
+    /// How large, in bytes of keys and values, the in-memory table can grow before it is flushed
+    /// to a new MTBL file. Default is 64 MiB.
+    pub memtable_bytes: Option<usize>,
+    /// Whether every write waits for the log to reach the disk, so that it survives a power
+    /// failure and not only a crash. Default is false.
+    pub sync: Option<bool>,
+    /// How often, in seconds, to look for files to compact in the background. 0 disables
+    /// background compaction. Default is 60 seconds.
+    pub compaction_interval_seconds: Option<u32>,
+    /// The options used to choose which files to compact. Default is the `Compactor` defaults.
+    pub compactor_options: Option<CompactorOptions>,
+    /// The options used to write flushed files. Default is the `Writer` defaults.
+    pub writer_options: Option<WriterOptions>,
+}
+
+impl StoreOptions {
+    /// Create a `StoreOptions` with only defaults.
+    pub fn new() -> StoreOptions {
+        StoreOptions {
+            memtable_bytes: None,
+            sync: None,
+            compaction_interval_seconds: None,
+            compactor_options: None,
+            writer_options: None,
+        }
+    }
+
+    /// Create a modified `StoreOptions` with memtable_bytes set.
+    pub fn memtable_bytes(self: &Self, memtable_bytes: usize) -> StoreOptions {
+        StoreOptions { memtable_bytes: Some(memtable_bytes), ..*self }
+    }
+
+    /// Create a modified `StoreOptions` with sync set.
+    pub fn sync(self: &Self, sync: bool) -> StoreOptions {
+        StoreOptions { sync: Some(sync), ..*self }
+    }
+
+    /// Create a modified `StoreOptions` with compaction_interval_seconds set.
+    pub fn compaction_interval_seconds(self: &Self, seconds: u32) -> StoreOptions {
+        StoreOptions { compaction_interval_seconds: Some(seconds), ..*self }
+    }
+
+    /// Create a modified `StoreOptions` with compactor_options set.
+    pub fn compactor_options(self: &Self, compactor_options: CompactorOptions) -> StoreOptions {
+        StoreOptions { compactor_options: Some(compactor_options), ..*self }
+    }
+
+    /// Create a modified `StoreOptions` with writer_options set.
+    pub fn writer_options(self: &Self, writer_options: WriterOptions) -> StoreOptions {
+        StoreOptions { writer_options: Some(writer_options), ..*self }
+    }
+
+    /// Open the `Store` in a directory with these options, creating the
+    /// directory if needed. Writes left in the log by a previous `Store` are
+    /// replayed.
+    pub fn open<T: AsRef<Path>>(self: &Self, dir: T) -> IOResult<Store> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir)?;
+        let lock = lock_dir(dir)?;
+        let setfile = dir.join(SETFILE_NAME);
+        OpenOptions::new().create(true).append(true).open(&setfile)?;
+        let fileset = Arc::new(Fileset::open_from_path(&setfile, Box::new(tombstone::merge))?);
+
+        let log_path = dir.join(LOG_NAME);
+        if !log_path.exists() {
+            reset_log(&log_path, 0)?;
+        }
+        let (memtable, bytes, log_seq) = replay_log(&log_path)?;
+        let seq = tombstone::max_seq(&memtable).unwrap_or(0).max(log_seq);
+        let log = OpenOptions::new().append(true).open(&log_path)?;
+        let log_len = log.metadata()?.len();
+
+        let compactor = Arc::new(self.compactor_options
+                                     .unwrap_or_else(CompactorOptions::new)
+                                     .tombstones(true)
+                                     .create(fileset.clone()));
+        let interval = self.compaction_interval_seconds
+                           .unwrap_or(DEFAULT_COMPACTION_INTERVAL_SECONDS);
+        let background = if interval == 0 {
+            None
+        } else {
+            Some(compactor.spawn_shared(Duration::from_secs(u64::from(interval))))
+        };
+        Ok(Store {
+            options: *self,
+            dir: dir.to_path_buf(),
+            compactor,
+            fileset,
+            state: Mutex::new(State {
+                memtable: Arc::new(memtable),
+                frozen: Vec::new(),
+                view: None,
+                bytes,
+                seq,
+                log,
+                log_len,
+            }),
+            background,
+            _lock: lock,
+        })
+    }
+}
+
+/// An updatable key-value store kept in a directory of MTBL files. See the
+/// [module documentation](index.html) for how it works.
+///
+/// All methods take `&self`, so a `Store` can be shared between threads.
+/// Reads never see a write half-done, and always see every write that has
+/// returned.
+///
+/// A write that fails to reach the log is not applied. But a write that fills
+/// the in-memory table also flushes it, and if the flush fails the write
+/// returns that error even though it was logged and is visible; the table is
+/// flushed again by a later write. Puts and deletes can be retried safely
+/// either way.
+pub struct Store {
+    /// The options used to open this `Store`.
+    pub options: StoreOptions,
+    dir: PathBuf,
+    fileset: Arc<Fileset>,
+    // Shared with the background thread, so that files compacted away by
+    // either are deleted once unused.
+    compactor: Arc<Compactor>,
+    state: Mutex<State>,
+    background: Option<CompactorThread>,
+    _lock: File,
+}
+
+/// The writable part of a `Store`.
+struct State {
+    /// The latest writes since the last flush, tombstone-encoded. Reads share
+    /// it; if it is written to while a `Scan` still has it, it is frozen
+    /// instead, and a new one started.
+    memtable: Arc<MemTable>,
+    /// The earlier writes since the last flush, in tables that are no longer
+    /// written to, oldest first.
+    frozen: Vec<Arc<MemTable>>,
+    /// The view of the in-memory tables and the files of a fileset generation
+    /// that reads share, so that the range deletes are only loaded once.
+    /// Writes drop it.
+    view: Option<(u64, Arc<View>)>,
+    /// The size of the keys and values added to the in-memory tables.
+    bytes: usize,
+    /// The sequence number of the latest write.
+    seq: u64,
+    /// The write-ahead log, holding everything in `memtable`.
+    log: File,
+    /// The length of the log, up to the end of its last whole record.
+    log_len: u64,
+}
+
+impl Store {
+    /// Open the `Store` in a directory with the default options, creating the
+    /// directory if needed.
+    pub fn open<T: AsRef<Path>>(dir: T) -> IOResult<Store> {
+        StoreOptions::new().open(dir)
+    }
+
+    /// The directory holding the store.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// The `Fileset` of flushed MTBL files. Its entries are tombstone-encoded.
+    pub fn fileset(&self) -> &Arc<Fileset> {
+        &self.fileset
+    }
+
+    /// Get the value of a key, if it's present and not deleted.
+    pub fn get<T: AsRef<[u8]>>(&self, key: T) -> Option<Vec<u8>> {
+        self.view().get(key)
+    }
+
+    /// Set the value of a key. An error from the flush that the write may
+    /// trigger comes after the write is applied; see [`Store`](struct.Store.html).
+    pub fn put<T, U>(&self, key: T, value: U) -> IOResult<()>
+        where T: AsRef<[u8]>,
+              U: AsRef<[u8]>
+    {
+        self.write(|seq| tombstone::put_entry(key, value, seq))
+    }
+
+    /// Delete a key, if it's present. An error from the flush that the write
+    /// may trigger comes after the delete is applied; see
+    /// [`Store`](struct.Store.html).
+    pub fn delete<T: AsRef<[u8]>>(&self, key: T) -> IOResult<()> {
+        self.write(|seq| tombstone::delete_entry(key, seq))
+    }
+
+    /// Delete every key in `[start, end)`. Every read consults every range
+    /// delete that hasn't been compacted away, so use them sparingly. As with
+    /// [`delete`](#method.delete), an error may come after the delete is
+    /// applied.
+    pub fn delete_range<T, U>(&self, start: T, end: U) -> IOResult<()>
+        where T: AsRef<[u8]>,
+              U: AsRef<[u8]>
+    {
+        self.write(|seq| tombstone::range_delete_entry(start, end, seq))
+    }
+
+    /// Iterate over the live keys and values between key0 and key1
+    /// (inclusive), as of when the scan starts: writes made while scanning
+    /// aren't seen.
+    pub fn scan<T, U>(&self, key0: T, key1: U) -> Scan
+        where T: AsRef<[u8]>,
+              U: AsRef<[u8]>
+    {
+        Scan {
+            view: self.view(),
+            next: Some(key0.as_ref().to_vec()),
+            end: Some(key1.as_ref().to_vec()),
+            batch: VecDeque::new(),
+        }
+    }
+
+    /// Iterate over every live key and value, as of when the scan starts.
+    pub fn iter(&self) -> Scan {
+        Scan {
+            view: self.view(),
+            next: Some(Vec::new()),
+            end: None,
+            batch: VecDeque::new(),
+        }
+    }
+
+    /// Write the in-memory table out as a new MTBL file and start a new log,
+    /// even if the table isn't full. Does nothing if there have been no writes
+    /// since the last flush.
+    pub fn flush(&self) -> IOResult<()> {
+        let mut state = self.state.lock().unwrap();
+        self.flush_locked(&mut state)
+    }
+
+    /// Compact the store's files until there is nothing left to merge,
+    /// returning the number of files written. See
+    /// [`Compactor::compact`](../struct.Compactor.html#method.compact).
+    pub fn compact(&self) -> IOResult<usize> {
+        self.compactor.compact()
+    }
+
+    /// A consistent view of the store: the files and in-memory table as they
+    /// are now.
+    fn view(&self) -> Arc<View> {
+        let mut state = self.state.lock().unwrap();
+        let snapshot = self.fileset.snapshot();
+        if let Some((generation, ref view)) = state.view {
+            if generation == snapshot.generation() {
+                return view.clone();
+            }
+        }
+        let generation = snapshot.generation();
+        let mut sources: Vec<Box<dyn Read + Send + Sync>> = vec![Box::new(snapshot)];
+        for memtable in state.frozen.iter().chain(Some(&state.memtable)) {
+            sources.push(Box::new(memtable.clone()));
+        }
+        let view = Arc::new(Merger::tombstoned(sources));
+        state.view = Some((generation, view.clone()));
+        view
+    }
+
+    /// Log and apply one write, made with the next sequence number.
+    fn write<F>(&self, entry: F) -> IOResult<()>
+        where F: FnOnce(u64) -> (Vec<u8>, Vec<u8>)
+    {
+        let mut state = self.state.lock().unwrap();
+        let (key, value) = entry(state.seq + 1);
+        let mut record = Vec::with_capacity(key.len() + value.len() + 8);
+        record.extend_from_slice(&(key.len() as u32).to_be_bytes());
+        record.extend_from_slice(&(value.len() as u32).to_be_bytes());
+        record.extend_from_slice(&key);
+        record.extend_from_slice(&value);
+        let sync = self.options.sync.unwrap_or(false);
+        let logged = state.log
+                          .write_all(&record)
+                          .and_then(|_| if sync { state.log.sync_data() } else { Ok(()) });
+        if let Err(e) = logged {
+            // Cut off any part of the record that was written, so that the
+            // next one doesn't follow it.
+            let log_len = state.log_len;
+            let _ = state.log.set_len(log_len);
+            return Err(e);
+        }
+        state.log_len += record.len() as u64;
+        state.seq += 1;
+        state.bytes += key.len() + value.len();
+        state.view = None;
+        if Arc::get_mut(&mut state.memtable).is_none() {
+            let fresh = Arc::new(MemTable::with_merge_fn(tombstone::merge));
+            let frozen = mem::replace(&mut state.memtable, fresh);
+            state.frozen.push(frozen);
+        }
+        Arc::get_mut(&mut state.memtable).unwrap().add(key, value).unwrap();
+        if state.bytes >= self.options.memtable_bytes.unwrap_or(DEFAULT_MEMTABLE_BYTES) {
+            self.flush_locked(&mut state)?;
+        }
+        Ok(())
+    }
+
+    fn flush_locked(&self, state: &mut State) -> IOResult<()> {
+        if state.memtable.is_empty() && state.frozen.is_empty() {
+            return Ok(());
+        }
+        let name = format!("table-{:020}.mtbl", state.seq);
+        let path = self.dir.join(&name);
+        let temp = self.dir.join(format!(".{}.tmp", name));
+        let written = (|| {
+            let mut writer = self.options
+                                 .writer_options
+                                 .unwrap_or_else(WriterOptions::new)
+                                 .create_from_path(&temp)?;
+            let memtables: Vec<&MemTable> =
+                state.frozen.iter().chain(Some(&state.memtable)).map(|m| &**m).collect();
+            let merger = Merger::new(memtables, tombstone::merge);
+            for (key, value) in merger.iter() {
+                writer.add(key, value).unwrap();
+            }
+            // Dropping the writer finishes the file.
+            drop(writer);
+            File::open(&temp)?.sync_all()?;
+            fs::rename(&temp, &path)
+        })();
+        if written.is_err() {
+            let _ = fs::remove_file(&temp);
+        }
+        written?;
+        self.fileset.add_file(&path)?;
+        // If this fails the old log is kept, and replaying it on the next open
+        // only repeats writes that are already in the new file.
+        reset_log(&self.dir.join(LOG_NAME), state.seq)?;
+        state.log = OpenOptions::new().append(true).open(self.dir.join(LOG_NAME))?;
+        state.log_len = state.log.metadata()?.len();
+        state.memtable = Arc::new(MemTable::with_merge_fn(tombstone::merge));
+        state.frozen.clear();
+        state.view = None;
+        state.bytes = 0;
+        Ok(())
+    }
+}
+
+impl Drop for Store {
+    fn drop(&mut self) {
+        if let Some(background) = self.background.take() {
+            let _ = background.stop();
+        }
+    }
+}
+
+/// The merged, tombstone-decoded view that reads go through.
+type View = Tombstoned<Merger<Box<dyn Read + Send + Sync>>>;
+
+/// An iterator over the live keys and values of a `Store`, from
+/// [`Store::scan`](struct.Store.html#method.scan) or
+/// [`Store::iter`](struct.Store.html#method.iter).
+///
+/// A scan keeps the files and in-memory table it started with, so it sees a
+/// consistent state of the store however long it runs; files that have been
+/// compacted away are deleted only once no scan is reading them.
+pub struct Scan {
+    view: Arc<View>,
+    /// The key to read the next batch from, or `None` when done.
+    next: Option<Vec<u8>>,
+    /// The last key to read, if any.
+    end: Option<Vec<u8>>,
+    batch: VecDeque<(Vec<u8>, Vec<u8>)>,
+}
+
+impl Scan {
+    fn read_batch(&mut self) {
+        let start = match self.next.take() {
+            Some(start) => start,
+            None => return,
+        };
+        self.batch = match self.end {
+            Some(ref end) => self.view.get_range(&start, end).take(SCAN_BATCH).collect(),
+            None => self.view.get_from(&start).take(SCAN_BATCH).collect(),
+        };
+        if self.batch.len() == SCAN_BATCH {
+            // Continue from just after the last key read.
+            let mut next = self.batch.back().unwrap().0.clone();
+            next.push(0);
+            self.next = Some(next);
+        }
+    }
+}
+
+impl Iterator for Scan {
+    type Item = (Vec<u8>, Vec<u8>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.batch.is_empty() {
+            self.read_batch();
+        }
+        self.batch.pop_front()
+    }
+}
+
+/// Take an exclusive lock on a store's directory.
+fn lock_dir(dir: &Path) -> IOResult<File> {
+    let lock = OpenOptions::new().create(true).append(true).open(dir.join(LOCK_NAME))?;
+    if unsafe { libc::flock(lock.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+        let e = Error::last_os_error();
+        if e.kind() == ErrorKind::WouldBlock {
+            return Err(Error::new(ErrorKind::WouldBlock,
+                                  format!("MTBL store {} is already open", dir.display())));
+        }
+        return Err(e);
+    }
+    Ok(lock)
+}
+
+/// Replace the log with an empty one that starts at sequence number `seq`.
+///
+/// A log is the sequence number of the last write before it, as 8 big-endian
+/// bytes, followed by one record per write: the lengths of the encoded key and
+/// value, as 4 big-endian bytes each, then the key and value.
+fn reset_log(path: &Path, seq: u64) -> IOResult<()> {
+    let temp = path.with_extension("log.tmp");
+    {
+        let mut f = File::create(&temp)?;
+        f.write_all(&seq.to_be_bytes())?;
+        f.sync_all()?;
+    }
+    fs::rename(&temp, path)?;
+    if let Some(dir) = path.parent() {
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+/// Read back the writes in a log, returning the table they make up, its size,
+/// and the log's starting sequence number. A record cut short by a crash ends
+/// the log, and is removed from the file.
+fn replay_log(path: &Path) -> IOResult<(MemTable, usize, u64)> {
+    let mut memtable = MemTable::with_merge_fn(tombstone::merge);
+    let mut contents = Vec::new();
+    File::open(path)?.read_to_end(&mut contents)?;
+    if contents.len() < 8 {
+        return Err(Error::new(ErrorKind::InvalidData,
+                              format!("MTBL store log {} is truncated", path.display())));
+    }
+    let mut seq = [0; 8];
+    seq.copy_from_slice(&contents[..8]);
+    let mut pos = 8;
+    let mut bytes = 0;
+    while let Some((key, value)) = log_record(&contents[pos..]) {
+        memtable.add(key, value).unwrap();
+        bytes += key.len() + value.len();
+        pos += key.len() + value.len() + 8;
+    }
+    if pos < contents.len() {
+        OpenOptions::new().write(true).open(path)?.set_len(pos as u64)?;
+    }
+    Ok((memtable, bytes, u64::from_be_bytes(seq)))
+}
+
+/// Decode the log record at the start of `data`, if there is a whole one.
+fn log_record(data: &[u8]) -> Option<(&[u8], &[u8])> {
+    let mut len = [0; 4];
+    len.copy_from_slice(data.get(0..4)?);
+    let key_len = u32::from_be_bytes(len) as usize;
+    len.copy_from_slice(data.get(4..8)?);
+    let value_len = u32::from_be_bytes(len) as usize;
+    let key = data.get(8..8 + key_len)?;
+    let value = data.get(8 + key_len..8 + key_len + value_len)?;
+    Some((key, value))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, OpenOptions};
+    use std::io::Write;
+
+    use compactor::CompactorOptions;
+    use reader::Read;
+    use store::{Store, StoreOptions, LOG_NAME};
+    use test_util::{v, TempDir};
+
+    #[test]
+    fn test_store() {
+        let dir = TempDir::new();
+        let store = StoreOptions::new().compaction_interval_seconds(0).open(dir.path()).unwrap();
+        assert!(Store::open(dir.path()).is_err());
+        for i in 0..10 {
+            store.put(format!("k{}", i), format!("v{}", i)).unwrap();
+        }
+        store.flush().unwrap();
+        store.put("k1", "new").unwrap();
+        store.delete("k2").unwrap();
+        store.delete_range("k5", "k8").unwrap();
+        assert_eq!(store.get("k1"), Some(v("new")));
+        assert_eq!(store.get("k2"), None);
+        assert_eq!(store.get("k3"), Some(v("v3")));
+        assert_eq!(store.get("k6"), None);
+
+        // A scan keeps seeing the store as it was when it started.
+        let scan = store.scan("k0", "k4");
+        store.put("k3", "newer").unwrap();
+        store.put("k4", "newer").unwrap();
+        store.delete("k4").unwrap();
+        // The table the scan reads was frozen rather than copied.
+        assert_eq!(store.state.lock().unwrap().frozen.len(), 1);
+        assert_eq!(store.get("k3"), Some(v("newer")));
+        assert_eq!(store.get("k4"), None);
+        assert_eq!(scan.collect::<Vec<_>>(),
+                   vec![(v("k0"), v("v0")), (v("k1"), v("new")), (v("k3"), v("v3")),
+                        (v("k4"), v("v4"))]);
+        assert_eq!(store.iter().map(|(k, _)| k).collect::<Vec<_>>(),
+                   vec![v("k0"), v("k1"), v("k3"), v("k8"), v("k9")]);
+
+        // Writes since the flush are replayed from the log on reopening.
+        let expected: Vec<_> = store.iter().collect();
+        drop(store);
+        let store = StoreOptions::new().compaction_interval_seconds(0).open(dir.path()).unwrap();
+        assert_eq!(store.iter().collect::<Vec<_>>(), expected);
+        assert_eq!(store.fileset().files().len(), 1);
+        store.flush().unwrap();
+        store.put("k0", "after").unwrap();
+        drop(store);
+        let store = Store::open(dir.path()).unwrap();
+        assert_eq!(store.get("k0"), Some(v("after")));
+        assert_eq!(store.get("k3"), Some(v("newer")));
+    }
+
+    #[test]
+    fn test_flush_and_compact() {
+        let dir = TempDir::new();
+        let compactor_options = CompactorOptions::new().min_files(2).size_ratio(1e9);
+        let store = StoreOptions::new()
+                        .memtable_bytes(1000)
+                        .compaction_interval_seconds(0)
+                        .compactor_options(compactor_options)
+                        .open(dir.path())
+                        .unwrap();
+        // Encoded puts are 32 bytes and deletes 17, so this flushes every 30
+        // writes or so.
+        for i in 0..400 {
+            store.put(format!("key{:04}", i % 100), format!("value {:09}", i)).unwrap();
+            if i % 7 == 0 {
+                store.delete(format!("key{:04}", (i + 50) % 100)).unwrap();
+            }
+        }
+        assert!(store.fileset().files().len() > 10);
+        let expected: Vec<_> = store.iter().collect();
+        assert!(store.compact().unwrap() > 0);
+        assert_eq!(store.fileset().files().len(), 1);
+        assert_eq!(store.iter().collect::<Vec<_>>(), expected);
+        // Merging down to the oldest file dropped the deletes and what they
+        // hid.
+        assert_eq!(store.fileset().iter().count(), expected.len());
+        assert_eq!(store.get("key0099"), Some(v("value 000000399")));
+    }
+
+    #[test]
+    fn test_torn_log() {
+        let dir = TempDir::new();
+        {
+            let store = Store::open(dir.path()).unwrap();
+            store.put("one", "1").unwrap();
+            store.put("two", "2").unwrap();
+        }
+        // Simulate a crash in the middle of appending a record.
+        let log = dir.path().join(LOG_NAME);
+        let len = fs::metadata(&log).unwrap().len();
+        OpenOptions::new().append(true).open(&log).unwrap().write_all(&[0, 0, 0, 9, 0]).unwrap();
+        let store = Store::open(dir.path()).unwrap();
+        assert_eq!(fs::metadata(&log).unwrap().len(), len);
+        assert_eq!(store.get("one"), Some(v("1")));
+        store.put("three", "3").unwrap();
+        drop(store);
+        let store = Store::open(dir.path()).unwrap();
+        assert_eq!(store.iter().count(), 3);
+    }
+}
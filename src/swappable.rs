@@ -0,0 +1,363 @@
+use std::io::Result as IOResult;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use fileset::FileIdentity;
+use mtbl_sys;
+use reader::{Iter, Nearest, Query, Read, Reader, ReaderOptions};
+use source::{CustomSource, SourceImpl, SourceIter};
+
+/// The default interval between checks for a new file.
+const DEFAULT_RELOAD_INTERVAL_SECONDS: u32 = 60;
+
+/// Options for opening a `SwappableReader`.
+///
+/// # Examples
+///
+/// ```
+/// SwappableReaderOptions::new().reload_interval_seconds(10).open_from_path("/srv/table.mtbl")
+/// ```
+#[derive(Clone,Copy)]
+pub struct SwappableReaderOptions {
+    /// How often, in seconds, to check whether a new file has appeared at the path. Default is 60
+    /// seconds.
+    pub reload_interval_seconds: Option<u32>,
+    /// The options used to open the file. Default is the `Reader` defaults.
+    pub reader_options: Option<ReaderOptions>,
+}
+
+impl SwappableReaderOptions {
+    /// Create a `SwappableReaderOptions` with only defaults.
+    pub fn new() -> SwappableReaderOptions {
+        SwappableReaderOptions {
+            reload_interval_seconds: None,
+            reader_options: None,
+        }
+    }
+
+    /// Create a modified `SwappableReaderOptions` with reload_interval_seconds set.
+    pub fn reload_interval_seconds(self: &Self,
+                                   reload_interval_seconds: u32)
+                                   -> SwappableReaderOptions {
+        SwappableReaderOptions { reload_interval_seconds: Some(reload_interval_seconds), ..*self }
+    }
+
+    /// Create a modified `SwappableReaderOptions` with reader_options set.
+    pub fn reader_options(self: &Self, reader_options: ReaderOptions) -> SwappableReaderOptions {
+        SwappableReaderOptions { reader_options: Some(reader_options), ..*self }
+    }
+
+    /// Open a `SwappableReader` with these options that serves the MTBL file
+    /// at a path, and swaps in the file found there whenever it is replaced.
+    pub fn open_from_path<T: AsRef<Path>>(self: &Self, path: T) -> IOResult<SwappableReader> {
+        let path = path.as_ref();
+        let identity = FileIdentity::of(path)?;
+        let reader = self.reader_options.unwrap_or_else(ReaderOptions::new).open_from_path(path)?;
+        Ok(self.create(Some(path.to_path_buf()), reader, Some(identity)))
+    }
+
+    fn create(&self,
+              path: Option<PathBuf>,
+              reader: Reader,
+              identity: Option<FileIdentity>)
+              -> SwappableReader {
+        SwappableReader {
+            options: *self,
+            source: CustomSource::new(SwappableSource {
+                options: *self,
+                path,
+                current: RwLock::new(Arc::new(reader)),
+                identity: Mutex::new(identity),
+                last_reload: Mutex::new(Instant::now()),
+                swaps: AtomicU64::new(0),
+            }),
+        }
+    }
+}
+
+/// A `Reader` that can be replaced while it is being read, for serving a table
+/// that is regenerated as a whole.
+///
+/// Every read goes to the current `Reader`. Replacing it, whether by calling
+/// [`swap`](#method.swap) or, for a `SwappableReader` opened from a path,
+/// because a new file has been renamed into place there, takes effect for all
+/// threads at once. Iterators that are already open keep reading from the
+/// `Reader` they started with, which stays open (and its file mapped) until
+/// the last of them is dropped.
+///
+/// A path is checked for a new file at most once per reload interval, on the
+/// next read after the interval is up. If the new file can't be opened (for
+/// example, because it is still being written in place), the current `Reader`
+/// keeps being served and the file is tried again after the next interval.
+///
+/// # Examples
+///
+/// ```
+/// let table = SwappableReader::open_from_path("/srv/table.mtbl")?;
+/// // Elsewhere, every day:
+/// // $ cp new-table.mtbl /srv/.table.mtbl.tmp && mv /srv/.table.mtbl.tmp /srv/table.mtbl
+/// let value = table.get("key");
+/// ```
+pub struct SwappableReader {
+    /// The options used to open this `SwappableReader`.
+    pub options: SwappableReaderOptions,
+    source: CustomSource<SwappableSource>,
+}
+
+impl SwappableReader {
+    /// Create a `SwappableReader` that serves `reader` until it is swapped for
+    /// another with [`swap`](#method.swap).
+    pub fn new(reader: Reader) -> SwappableReader {
+        SwappableReaderOptions::new().create(None, reader, None)
+    }
+
+    /// Open a `SwappableReader` that serves the MTBL file at a path, and swaps
+    /// in the file found there whenever it is replaced.
+    pub fn open_from_path<T: AsRef<Path>>(path: T) -> IOResult<SwappableReader> {
+        SwappableReaderOptions::new().open_from_path(path)
+    }
+
+    /// The path being served, if this was opened from a path.
+    pub fn path(&self) -> Option<&Path> {
+        self.source.inner().path.as_deref()
+    }
+
+    /// Get the `Reader` currently being served (after checking for a new file,
+    /// if a check is due). Holding on to it keeps it open, so that several
+    /// reads can be made from the same version of the table.
+    pub fn current(&self) -> Arc<Reader> {
+        self.source.inner().reader()
+    }
+
+    /// Start serving `reader` instead of the current `Reader`, which is
+    /// returned. For a `SwappableReader` opened from a path, the new `Reader`
+    /// is served until a new file appears at the path.
+    pub fn swap(&self, reader: Reader) -> Arc<Reader> {
+        let source = self.source.inner();
+        let old = mem::replace(&mut *source.current.write().unwrap(), Arc::new(reader));
+        source.swaps.fetch_add(1, Ordering::SeqCst);
+        old
+    }
+
+    /// Check for a new file at the path right away, regardless of the reload
+    /// interval, returning whether one was swapped in. Fails if there is a new
+    /// file that can't be opened, in which case the current `Reader` is kept.
+    pub fn reload_now(&self) -> IOResult<bool> {
+        self.source.inner().reload()
+    }
+}
+
+impl Read for SwappableReader {
+    fn raw_mtbl_source(&self) -> &*const mtbl_sys::mtbl_source {
+        self.source.raw_mtbl_source()
+    }
+
+    fn query(&self, query: Query) -> Iter<'_> {
+        self.source.query(query)
+    }
+
+    /// The number of times the `Reader` has been replaced.
+    fn content_version(&self) -> Option<u64> {
+        Some(self.source.inner().swaps.load(Ordering::SeqCst))
+    }
+
+    fn get_sorted(&self, keys: &[&[u8]]) -> Vec<Option<Vec<u8>>> {
+        self.source.inner().reader().get_sorted(keys)
+    }
+
+    fn nearest(&self, target: Nearest) -> Option<(Vec<u8>, Vec<u8>)> {
+        self.source.inner().reader().nearest(target)
+    }
+}
+
+impl<'a> IntoIterator for &'a SwappableReader {
+    type Item = (Vec<u8>, Vec<u8>);
+    type IntoIter = Iter<'a>;
+
+    fn into_iter(self) -> Iter<'a> {
+        self.iter()
+    }
+}
+
+/// The state behind a `SwappableReader`, read as a custom source so that every
+/// read can check for a new file first.
+struct SwappableSource {
+    options: SwappableReaderOptions,
+    path: Option<PathBuf>,
+    current: RwLock<Arc<Reader>>,
+    // The identity of the file at `path` when it was last opened. Also held
+    // while reloading, so that only one thread opens a new file.
+    identity: Mutex<Option<FileIdentity>>,
+    last_reload: Mutex<Instant>,
+    // The number of times `current` has been replaced.
+    swaps: AtomicU64,
+}
+
+impl SwappableSource {
+    /// Swap in the file at the path if it has changed since it was opened.
+    fn reload(&self) -> IOResult<bool> {
+        let path = match self.path {
+            Some(ref path) => path,
+            None => return Ok(false),
+        };
+        let mut identity = self.identity.lock().unwrap();
+        let new_identity = FileIdentity::of(path)?;
+        if identity.as_ref() == Some(&new_identity) {
+            return Ok(false);
+        }
+        let reader = self.options
+                         .reader_options
+                         .unwrap_or_else(ReaderOptions::new)
+                         .open_from_path(path)?;
+        *self.current.write().unwrap() = Arc::new(reader);
+        self.swaps.fetch_add(1, Ordering::SeqCst);
+        *identity = Some(new_identity);
+        Ok(true)
+    }
+
+    /// Check for a new file if the reload interval has passed.
+    fn maybe_reload(&self) {
+        if self.path.is_none() {
+            return;
+        }
+        let interval = self.options
+                           .reload_interval_seconds
+                           .unwrap_or(DEFAULT_RELOAD_INTERVAL_SECONDS);
+        {
+            let mut last_reload = self.last_reload.lock().unwrap();
+            if last_reload.elapsed() < Duration::from_secs(u64::from(interval)) {
+                return;
+            }
+            *last_reload = Instant::now();
+        }
+        let _ = self.reload();
+    }
+
+    /// Get the current `Reader`, checking for a new file first if it is due.
+    fn reader(&self) -> Arc<Reader> {
+        self.maybe_reload();
+        self.current.read().unwrap().clone()
+    }
+}
+
+/// An iterator over a `Reader` that keeps it open, so that a swap cannot close
+/// the file it is reading.
+struct ReaderIter {
+    // Declared before `_reader` so that it is dropped first.
+    iter: Iter<'static>,
+    _reader: Arc<Reader>,
+}
+
+impl Iterator for ReaderIter {
+    type Item = (Vec<u8>, Vec<u8>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.iter.next()
+    }
+}
+
+fn pinned<F>(reader: Arc<Reader>, f: F) -> SourceIter<'static>
+    where F: for<'r> FnOnce(&'r Reader) -> Iter<'r>
+{
+    // The reader is kept alive for as long as the iterator, so the borrow can
+    // be erased.
+    let iter: Iter<'static> = unsafe { mem::transmute(f(&reader)) };
+    Box::new(ReaderIter { iter, _reader: reader })
+}
+
+impl SourceImpl for SwappableSource {
+    fn iter(&self) -> SourceIter<'_> {
+        pinned(self.reader(), |r| r.iter())
+    }
+
+    fn get(&self, key: &[u8]) -> SourceIter<'_> {
+        pinned(self.reader(), |r| r.get_range(key, key))
+    }
+
+    fn get_prefix(&self, prefix: &[u8]) -> SourceIter<'_> {
+        pinned(self.reader(), |r| r.get_prefix(prefix))
+    }
+
+    fn get_range(&self, key0: &[u8], key1: &[u8]) -> SourceIter<'_> {
+        pinned(self.reader(), |r| r.get_range(key0, key1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::sync::Arc;
+    use std::thread;
+
+    use reader::{Read, Reader};
+    use swappable::{SwappableReader, SwappableReaderOptions};
+    use test_util::{create_mtbl, numbered, v, TempDir};
+    use writer::WriterOptions;
+
+    /// The keys "key0000" to "key0999", each with `version` as its value.
+    fn versioned(version: &str) -> Vec<(String, &str)> {
+        numbered(1000).into_iter().map(|(key, _)| (key, version)).collect()
+    }
+
+    #[test]
+    fn test_swap() {
+        let dir = TempDir::new();
+        create_mtbl(dir.path().join("a.mtbl"), WriterOptions::new(), &versioned("a"));
+        create_mtbl(dir.path().join("b.mtbl"), WriterOptions::new(), &versioned("b"));
+        let table = SwappableReader::new(Reader::open_from_path(dir.path().join("a.mtbl"))
+                                             .unwrap());
+        assert_eq!(table.path(), None);
+        let mut iter = table.iter();
+        assert_eq!(iter.next(), Some((v("key0000"), v("a"))));
+
+        let old = table.swap(Reader::open_from_path(dir.path().join("b.mtbl")).unwrap());
+        assert_eq!(old.count_entries(), 1000);
+        drop(old);
+        assert_eq!(table.get("key0001"), Some(v("b")));
+        assert_eq!(table.get_prefix("key09").count(), 100);
+        // The iterator opened before the swap keeps reading the old file.
+        assert_eq!(iter.next(), Some((v("key0001"), v("a"))));
+        assert_eq!(iter.count(), 998);
+        assert!(!table.reload_now().unwrap());
+    }
+
+    #[test]
+    fn test_replace_file() {
+        let dir = TempDir::new();
+        let path = dir.path().join("table.mtbl");
+        create_mtbl(&path, WriterOptions::new(), &versioned("a"));
+        let table = Arc::new(SwappableReaderOptions::new()
+                                 .reload_interval_seconds(0)
+                                 .open_from_path(&path)
+                                 .unwrap());
+        assert_eq!(table.path(), Some(path.as_path()));
+        let pinned = table.current();
+        let mut iter = table.iter();
+        assert_eq!(iter.next(), Some((v("key0000"), v("a"))));
+
+        let temp = dir.path().join(".table.mtbl.tmp");
+        create_mtbl(&temp, WriterOptions::new(), &versioned("b"));
+        fs::rename(&temp, &path).unwrap();
+        let readers: Vec<_> = (0..4)
+                                  .map(|_| {
+                                      let table = table.clone();
+                                      thread::spawn(move || table.get("key0500"))
+                                  })
+                                  .collect();
+        for reader in readers {
+            assert_eq!(reader.join().unwrap(), Some(v("b")));
+        }
+        assert_eq!(iter.map(|(_, v)| v).filter(|v| v == b"a").count(), 999);
+        assert_eq!(pinned.get("key0500"), Some(v("a")));
+        assert!(!table.reload_now().unwrap());
+
+        // A file that can't be opened leaves the old one in place.
+        fs::write(&temp, b"not an MTBL file").unwrap();
+        fs::rename(&temp, &path).unwrap();
+        assert!(table.reload_now().is_err());
+        assert_eq!(table.get("key0500"), Some(v("b")));
+    }
+}
@@ -0,0 +1,92 @@
+//! Helpers shared by the unit tests.
+
+use std::env;
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::io::Write as IOWrite;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+extern crate tempfile;
+use self::tempfile::NamedTempFile;
+
+use reader::Reader;
+use writer::{Write, Writer, WriterOptions};
+
+static NEXT_DIR: AtomicUsize = AtomicUsize::new(0);
+
+/// A temporary directory that is removed, with its contents, when dropped.
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    pub fn new() -> TempDir {
+        let n = NEXT_DIR.fetch_add(1, Ordering::SeqCst);
+        let path = env::temp_dir().join(format!("mtbl-test-{}-{}", process::id(), n));
+        fs::create_dir_all(&path).unwrap();
+        TempDir { path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+/// Create a test MTBL file at `path`, written with `options`.
+pub fn create_mtbl<P, K, V>(path: P, options: WriterOptions, entries: &[(K, V)])
+    where P: AsRef<Path>,
+          K: AsRef<[u8]>,
+          V: AsRef<[u8]>
+{
+    let mut writer = options.create_from_path(path).unwrap();
+    for (key, value) in entries {
+        writer.add(key, value).unwrap();
+    }
+}
+
+/// Write a setfile listing `names`, one per line.
+pub fn write_setfile<T: AsRef<OsStr>>(path: &Path, names: &[T]) {
+    let mut f = File::create(path).unwrap();
+    for name in names {
+        f.write_all(name.as_ref().as_bytes()).unwrap();
+        f.write_all(b"\n").unwrap();
+    }
+}
+
+/// The entries "key0000" = "value0", "key0001" = "value1" and so on, which
+/// span many blocks of 64 bytes.
+pub fn numbered(count: usize) -> Vec<(String, String)> {
+    (0..count).map(|i| (format!("key{:04}", i), format!("value{}", i))).collect()
+}
+
+/// Write `entries` to a temporary MTBL file and open a reader for it.
+pub fn create_reader(entries: &[(&str, &str)]) -> Reader {
+    let tempfile_writer = NamedTempFile::new().unwrap();
+    let tempfile_reader = tempfile_writer.reopen().unwrap();
+    {
+        let mut writer = Writer::create_from_file(tempfile_writer).unwrap();
+        for &(k, v) in entries {
+            writer.add(k, v).unwrap();
+        }
+    }
+    Reader::open_from_file(&tempfile_reader).unwrap()
+}
+
+/// Set up readers with collision for "one".
+pub fn set_up_readers() -> Vec<Reader> {
+    vec![create_reader(&[("one", "Hello"), ("two", "world")]),
+         create_reader(&[("one", "blue"), ("three", "green")])]
+}
+
+pub fn v(s: &str) -> Vec<u8> {
+    s.as_bytes().to_vec()
+}
@@ -0,0 +1,412 @@
+//! Tombstone-aware merging, for expressing deletes across layered MTBL files.
+//!
+//! An MTBL file can only add or override keys, so on its own a newer file
+//! cannot remove a key that an older file contains. This module defines an
+//! opt-in encoding in which every entry carries a sequence number and is
+//! either a value, a point delete, or a range delete:
+//!
+//! * Entries are created with [`put_entry`](fn.put_entry.html),
+//!   [`delete_entry`](fn.delete_entry.html) and
+//!   [`range_delete_entry`](fn.range_delete_entry.html), and written with a
+//!   `Writer`, `Sorter` or `MemTable`. Encoded keys sort differently from the
+//!   original keys, so when using a `Writer` directly, add the encoded entries
+//!   in encoded-key order.
+//! * Layers are combined with [`merge`](fn.merge.html) as the `MergeFn` of a
+//!   `Merger`, `Fileset` or `Sorter`. For every key the entry with the highest
+//!   sequence number wins, regardless of the order of the sources.
+//! * The merged source is read through [`Tombstoned`](struct.Tombstoned.html),
+//!   which decodes entries and hides deleted keys. `Merger::tombstoned` and
+//!   `Fileset::open_tombstoned` set up both the merge function and the view.
+//! * [`compact`](fn.compact.html) rewrites merged layers into a single file,
+//!   dropping tombstones once they reach the bottom layer.
+//!
+//! A range delete removes every key in `[start, end)` that was written with a
+//! lower sequence number. A view keeps all of its source's range deletes in
+//! memory and checks every key against them, so they are meant to be used
+//! sparingly.
+//!
+//! # Examples
+//!
+//! ```
+//! use mtbl::tombstone;
+//!
+//! let mut newer = MemTable::with_merge_fn(tombstone::merge);
+//! let (k, v) = tombstone::delete_entry("one", 2);
+//! newer.add(k, v);
+//! let view = Merger::tombstoned(vec![older_reader, newer_reader]);
+//! assert_eq!(view.get("one"), None);
+//! ```
+
+use std::cmp;
+use std::sync::{Arc, Mutex};
+
+use mtbl_sys;
+use reader::{Iter, Query, Read};
+use source::NO_MTBL_SOURCE;
+use writer::Write;
+
+/// Encoded keys of range deletes start with this byte, so that they can all be
+/// found with one prefix lookup.
+const RANGE_PREFIX: u8 = 0;
+/// Encoded keys of values and point deletes start with this byte.
+const POINT_PREFIX: u8 = 1;
+
+const KIND_PUT: u8 = 0;
+const KIND_DELETE: u8 = 1;
+
+/// A decoded point entry: a value (or a delete) and its sequence number.
+struct Point<'a> {
+    seq: u64,
+    value: Option<&'a [u8]>,
+}
+
+/// A decoded range delete covering `[start, end)`.
+struct RangeDelete {
+    start: Vec<u8>,
+    end: Vec<u8>,
+    seq: u64,
+}
+
+impl RangeDelete {
+    fn covers(&self, key: &[u8], seq: u64) -> bool {
+        self.seq > seq && &self.start[..] <= key && key < &self.end[..]
+    }
+}
+
+fn encode_key(prefix: u8, key: &[u8]) -> Vec<u8> {
+    let mut encoded = Vec::with_capacity(key.len() + 1);
+    encoded.push(prefix);
+    encoded.extend_from_slice(key);
+    encoded
+}
+
+fn encode_point(kind: u8, seq: u64, value: &[u8]) -> Vec<u8> {
+    let mut encoded = Vec::with_capacity(value.len() + 9);
+    encoded.push(kind);
+    encoded.extend_from_slice(&seq.to_be_bytes());
+    encoded.extend_from_slice(value);
+    encoded
+}
+
+fn decode_point(value: &[u8]) -> Option<Point<'_>> {
+    if value.len() < 9 {
+        return None;
+    }
+    let mut seq = [0; 8];
+    seq.copy_from_slice(&value[1..9]);
+    let seq = u64::from_be_bytes(seq);
+    match value[0] {
+        KIND_PUT => Some(Point { seq, value: Some(&value[9..]) }),
+        KIND_DELETE => Some(Point { seq, value: None }),
+        _ => None,
+    }
+}
+
+/// Decode the (possibly merged) list of range deletes stored under one start
+/// key. Malformed trailing data is ignored.
+fn decode_ranges(start: &[u8], mut value: &[u8], ranges: &mut Vec<RangeDelete>) {
+    while value.len() >= 12 {
+        let mut seq = [0; 8];
+        seq.copy_from_slice(&value[0..8]);
+        let mut len = [0; 4];
+        len.copy_from_slice(&value[8..12]);
+        let len = u32::from_be_bytes(len) as usize;
+        if value.len() < 12 + len {
+            break;
+        }
+        ranges.push(RangeDelete {
+            start: start.to_vec(),
+            end: value[12..12 + len].to_vec(),
+            seq: u64::from_be_bytes(seq),
+        });
+        value = &value[12 + len..];
+    }
+}
+
+/// Encode a key-value pair written with sequence number `seq`.
+pub fn put_entry<T, U>(key: T, value: U, seq: u64) -> (Vec<u8>, Vec<u8>)
+    where T: AsRef<[u8]>,
+          U: AsRef<[u8]>
+{
+    (encode_key(POINT_PREFIX, key.as_ref()), encode_point(KIND_PUT, seq, value.as_ref()))
+}
+
+/// Encode a delete of a single key with sequence number `seq`.
+pub fn delete_entry<T: AsRef<[u8]>>(key: T, seq: u64) -> (Vec<u8>, Vec<u8>) {
+    (encode_key(POINT_PREFIX, key.as_ref()), encode_point(KIND_DELETE, seq, &[]))
+}
+
+/// Encode a delete of every key in `[start, end)` with sequence number `seq`.
+pub fn range_delete_entry<T, U>(start: T, end: U, seq: u64) -> (Vec<u8>, Vec<u8>)
+    where T: AsRef<[u8]>,
+          U: AsRef<[u8]>
+{
+    let end = end.as_ref();
+    let mut value = Vec::with_capacity(end.len() + 12);
+    value.extend_from_slice(&seq.to_be_bytes());
+    value.extend_from_slice(&(end.len() as u32).to_be_bytes());
+    value.extend_from_slice(end);
+    (encode_key(RANGE_PREFIX, start.as_ref()), value)
+}
+
+/// A `MergeFn` for tombstone-encoded entries.
+///
+/// For values and point deletes, the entry with the higher sequence number wins
+/// (the second one, on a tie). Range deletes that start at the same key are all
+/// kept.
+pub fn merge(key: &[u8], val0: &[u8], val1: &[u8]) -> Vec<u8> {
+    if key.first() == Some(&RANGE_PREFIX) {
+        let mut merged = val0.to_vec();
+        merged.extend_from_slice(val1);
+        return merged;
+    }
+    match (decode_point(val0), decode_point(val1)) {
+        (Some(p0), Some(p1)) if p0.seq > p1.seq => val0.to_vec(),
+        (Some(_), None) => val0.to_vec(),
+        _ => val1.to_vec(),
+    }
+}
+
+/// A view of a tombstone-encoded source (usually a `Merger` or `Fileset` using
+/// [`merge`](fn.merge.html)) that decodes entries and hides deleted keys.
+///
+/// The easiest way to get one is with
+/// [`Merger::tombstoned`](../struct.Merger.html#method.tombstoned) or
+/// [`Fileset::open_tombstoned`](../struct.Fileset.html#method.open_tombstoned),
+/// which also set up the merge function. A `Tombstoned` is itself a
+/// [`Read`](../trait.Read.html), with the decoded keys and values.
+///
+/// The range deletes of the source are loaded on the first lookup and kept
+/// until the source's
+/// [`content_version`](../trait.Read.html#method.content_version) changes; for
+/// a source that doesn't report one, they are loaded for every lookup.
+pub struct Tombstoned<S: Read> {
+    source: S,
+    // The range deletes of `source`, and the version they were loaded at.
+    ranges: Mutex<Option<(u64, Arc<Vec<RangeDelete>>)>>,
+}
+
+impl<S: Read> Tombstoned<S> {
+    /// Create a view of a tombstone-encoded source.
+    pub fn new(source: S) -> Tombstoned<S> {
+        Tombstoned {
+            source,
+            ranges: Mutex::new(None),
+        }
+    }
+
+    /// The underlying tombstone-encoded source.
+    pub fn source(&self) -> &S {
+        &self.source
+    }
+
+    /// Unwrap the underlying tombstone-encoded source.
+    pub fn into_source(self) -> S {
+        self.source
+    }
+
+    fn range_deletes(&self) -> Arc<Vec<RangeDelete>> {
+        let version = self.source.content_version();
+        if let (Some(version), Some((cached, ranges))) = (version, &*self.ranges.lock().unwrap()) {
+            if version == *cached {
+                return ranges.clone();
+            }
+        }
+        let mut ranges = Vec::new();
+        for (key, value) in self.source.get_prefix([RANGE_PREFIX]) {
+            decode_ranges(&key[1..], &value, &mut ranges);
+        }
+        let ranges = Arc::new(ranges);
+        if let Some(version) = version {
+            *self.ranges.lock().unwrap() = Some((version, ranges.clone()));
+        }
+        ranges
+    }
+
+    fn live<'a>(&self, points: Iter<'a>) -> Iter<'a> {
+        let ranges = self.range_deletes();
+        Iter::from_entries(Box::new(points.filter_map(move |(key, value)| {
+            let point = decode_point(&value)?;
+            let live = point.value?;
+            if ranges.iter().any(|r| r.covers(&key[1..], point.seq)) {
+                return None;
+            }
+            Some((key[1..].to_vec(), live.to_vec()))
+        })))
+    }
+
+    /// Get an iterator over all live keys and values, where the keys are at
+    /// least key0.
+    pub fn get_from<T: AsRef<[u8]>>(&self, key0: T) -> Iter<'_> {
+        self.live(self.source.get_range(encode_key(POINT_PREFIX, key0.as_ref()),
+                                        [POINT_PREFIX + 1]))
+    }
+}
+
+impl<S: Read> Read for Tombstoned<S> {
+    fn raw_mtbl_source(&self) -> &*const mtbl_sys::mtbl_source {
+        &NO_MTBL_SOURCE
+    }
+
+    fn query(&self, query: Query) -> Iter<'_> {
+        match query {
+            Query::All => self.live(self.source.get_prefix([POINT_PREFIX])),
+            Query::Get(key) => self.live(self.source.query(Query::Get(&encode_key(POINT_PREFIX, key)))),
+            Query::Prefix(prefix) => {
+                self.live(self.source.get_prefix(encode_key(POINT_PREFIX, prefix)))
+            }
+            Query::Range(key0, key1) => {
+                self.live(self.source.get_range(encode_key(POINT_PREFIX, key0),
+                                                encode_key(POINT_PREFIX, key1)))
+            }
+        }
+    }
+
+    fn content_version(&self) -> Option<u64> {
+        self.source.content_version()
+    }
+}
+
+impl<'a, S: Read> IntoIterator for &'a Tombstoned<S> {
+    type Item = (Vec<u8>, Vec<u8>);
+    type IntoIter = Iter<'a>;
+
+    fn into_iter(self) -> Iter<'a> {
+        self.iter()
+    }
+}
+
+/// Rewrite a tombstone-encoded source (usually a `Merger` of several layers
+/// using [`merge`](fn.merge.html)) into a single layer.
+///
+/// Values hidden by a range delete in the source are always dropped. If
+/// `bottom` is true, the output will be the bottom layer, with nothing older
+/// for tombstones to hide, so point and range deletes are dropped too.
+/// Otherwise they are kept so that they still hide keys in older layers.
+///
+/// The output stays tombstone-encoded. This fails only if the writer rejects
+/// an entry.
+pub fn compact<S: Read, W: Write>(source: &S, writer: &mut W, bottom: bool) -> Result<(), ()> {
+    let mut ranges = Vec::new();
+    for (key, value) in source.get_prefix([RANGE_PREFIX]) {
+        decode_ranges(&key[1..], &value, &mut ranges);
+        if !bottom {
+            writer.add(&key, &value)?;
+        }
+    }
+    for (key, value) in source.get_prefix([POINT_PREFIX]) {
+        let keep = match decode_point(&value) {
+            Some(point) => {
+                let hidden = ranges.iter().any(|r| r.covers(&key[1..], point.seq));
+                !hidden && (point.value.is_some() || !bottom)
+            }
+            None => false,
+        };
+        if keep {
+            writer.add(&key, &value)?;
+        }
+    }
+    Ok(())
+}
+
+/// The highest sequence number used by any entry of a tombstone-encoded
+/// source, for picking the next one to write with.
+pub fn max_seq<S: Read>(source: &S) -> Option<u64> {
+    let mut max = None;
+    for (key, value) in source.iter() {
+        let seq = match key.first() {
+            Some(&RANGE_PREFIX) => {
+                let mut ranges = Vec::new();
+                decode_ranges(&key[1..], &value, &mut ranges);
+                ranges.iter().map(|r| r.seq).max()
+            }
+            Some(&POINT_PREFIX) => decode_point(&value).map(|p| p.seq),
+            _ => None,
+        };
+        max = cmp::max(max, seq);
+    }
+    max
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempfile;
+    use self::tempfile::NamedTempFile;
+
+    use memtable::MemTable;
+    use merger::Merger;
+    use reader::{Read, Reader};
+    use test_util::v;
+    use tombstone;
+    use tombstone::Tombstoned;
+    use writer::{Write, Writer};
+
+    fn add(table: &mut MemTable, entry: (Vec<u8>, Vec<u8>)) {
+        table.add(entry.0, entry.1).unwrap();
+    }
+
+    /// Three layers, listed newest first so that source order disagrees with
+    /// sequence order.
+    fn set_up_layers() -> Vec<MemTable> {
+        let mut base = MemTable::with_merge_fn(tombstone::merge);
+        for k in &["a", "b", "c", "d", "e"] {
+            add(&mut base, tombstone::put_entry(k, format!("old {}", k), 1));
+        }
+        let mut deletes = MemTable::with_merge_fn(tombstone::merge);
+        add(&mut deletes, tombstone::delete_entry("b", 2));
+        add(&mut deletes, tombstone::range_delete_entry("c", "e", 2));
+        let mut newest = MemTable::with_merge_fn(tombstone::merge);
+        add(&mut newest, tombstone::put_entry("d", "new d", 3));
+        add(&mut newest, tombstone::put_entry("f", "new f", 3));
+        vec![newest, deletes, base]
+    }
+
+    fn expected() -> Vec<(Vec<u8>, Vec<u8>)> {
+        vec![(v("a"), v("old a")), (v("d"), v("new d")), (v("e"), v("old e")), (v("f"), v("new f"))]
+    }
+
+    #[test]
+    fn test_tombstoned_merger() {
+        let merger = Merger::new(set_up_layers(), tombstone::merge);
+        let view = Tombstoned::new(merger);
+        assert_eq!(view.get("a"), Some(v("old a")));
+        assert_eq!(view.get("b"), None);
+        assert_eq!(view.get("c"), None);
+        assert_eq!(view.get("d"), Some(v("new d")));
+        assert_eq!(view.get("e"), Some(v("old e")));
+        assert_eq!(view.get("z"), None);
+        assert_eq!(view.iter().collect::<Vec<_>>(), expected());
+        assert_eq!(view.get_range("b", "d").collect::<Vec<_>>(), vec![(v("d"), v("new d"))]);
+        assert_eq!(view.get_prefix("e").count(), 1);
+        assert_eq!(tombstone::max_seq(view.source()), Some(3));
+
+        let view = Merger::tombstoned(set_up_layers());
+        assert_eq!(view.get("c"), None);
+        assert_eq!(view.get("e"), Some(v("old e")));
+        assert_eq!(view.iter().collect::<Vec<_>>(), expected());
+        // A view is itself a source, and can be merged with others.
+        let merger = Merger::new(vec![view], Merger::merge_choose_first_value);
+        assert_eq!(merger.iter().collect::<Vec<_>>(), expected());
+    }
+
+    #[test]
+    fn test_compact() {
+        let merger = Merger::new(set_up_layers(), tombstone::merge);
+
+        let mut upper = MemTable::new();
+        tombstone::compact(&merger, &mut upper, false).unwrap();
+        // "c" is hidden by the range delete; the tombstones are kept.
+        assert_eq!(upper.len(), 6);
+
+        let tempfile_writer = NamedTempFile::new().unwrap();
+        let tempfile_reader = tempfile_writer.reopen().unwrap();
+        {
+            let mut writer = Writer::create_from_file(tempfile_writer).unwrap();
+            tombstone::compact(&merger, &mut writer, true).unwrap();
+        }
+        let reader = Reader::open_from_file(&tempfile_reader).unwrap();
+        assert_eq!(reader.count_entries(), 4);
+        let view = Tombstoned::new(reader);
+        assert_eq!(view.iter().collect::<Vec<_>>(), expected());
+    }
+}
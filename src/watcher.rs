@@ -0,0 +1,167 @@
+//! Filesystem change notification for `Fileset`s.
+
+use std::collections::{HashMap, HashSet};
+use std::io::Result as IOResult;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Watches directories for files being created, written, renamed or deleted.
+///
+/// Notifications are collected by [`wait`](#method.wait), which reports
+/// whether anything has happened since it was last called.
+pub struct Watcher {
+    fd: i32,
+    // The watched directories, with their watch descriptors.
+    dirs: Mutex<HashMap<PathBuf, i32>>,
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use libc;
+    use std::ffi::CString;
+    use std::io::Result as IOResult;
+    use std::io::{Error, ErrorKind};
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::Path;
+    use std::time::Duration;
+
+    /// Everything that could change what a file name refers to or what the
+    /// file contains.
+    const EVENTS: u32 = libc::IN_CLOSE_WRITE | libc::IN_MODIFY | libc::IN_MOVED_TO |
+                        libc::IN_MOVED_FROM | libc::IN_CREATE | libc::IN_DELETE |
+                        libc::IN_ATTRIB;
+
+    pub fn init() -> IOResult<i32> {
+        let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(fd)
+    }
+
+    pub fn add_watch(fd: i32, dir: &Path) -> IOResult<i32> {
+        let dir = CString::new(dir.as_os_str().as_bytes())
+                      .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+        let wd = unsafe { libc::inotify_add_watch(fd, dir.as_ptr(), EVENTS | libc::IN_ONLYDIR) };
+        if wd < 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(wd)
+    }
+
+    pub fn rm_watch(fd: i32, wd: i32) {
+        // This fails only if the directory is already gone, which removes
+        // the watch anyway.
+        unsafe {
+            libc::inotify_rm_watch(fd, wd);
+        }
+    }
+
+    /// Wait until there are events or the timeout passes, then read and
+    /// discard all pending events, returning whether there were any. A queue
+    /// overflow is reported as an event like any other.
+    pub fn wait(fd: i32, timeout: Duration) -> bool {
+        let mut pollfd = libc::pollfd {
+            fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let timeout = timeout.as_millis().min(i32::MAX as u128) as i32;
+        unsafe {
+            libc::poll(&mut pollfd, 1, timeout);
+        }
+        let mut buf = [0u8; 4096];
+        let mut any = false;
+        loop {
+            let n = unsafe { libc::read(fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+            if n <= 0 {
+                return any;
+            }
+            any = true;
+        }
+    }
+
+    pub fn close(fd: i32) {
+        unsafe {
+            libc::close(fd);
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod sys {
+    use std::io::Result as IOResult;
+    use std::io::{Error, ErrorKind};
+    use std::path::Path;
+    use std::time::Duration;
+
+    pub fn init() -> IOResult<i32> {
+        Err(Error::new(ErrorKind::Other,
+                       "watching for file changes is only supported on Linux"))
+    }
+
+    pub fn add_watch(_fd: i32, _dir: &Path) -> IOResult<i32> {
+        unreachable!()
+    }
+
+    pub fn rm_watch(_fd: i32, _wd: i32) {
+        unreachable!()
+    }
+
+    pub fn wait(_fd: i32, _timeout: Duration) -> bool {
+        unreachable!()
+    }
+
+    pub fn close(_fd: i32) {}
+}
+
+impl Watcher {
+    /// Create a watcher that is not watching anything yet.
+    pub fn new() -> IOResult<Watcher> {
+        Ok(Watcher {
+            fd: sys::init()?,
+            dirs: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Watch the entries of exactly these directories: start watching the
+    /// ones not watched yet, and stop watching the rest. An empty path means
+    /// the current directory.
+    pub fn set_dirs(&self, dirs: &HashSet<PathBuf>) -> IOResult<()> {
+        let mut watched = self.dirs.lock().unwrap();
+        for dir in dirs {
+            if watched.contains_key(dir) {
+                continue;
+            }
+            let path = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+            let wd = sys::add_watch(self.fd, path)?;
+            watched.insert(dir.clone(), wd);
+        }
+        let stale: Vec<(PathBuf, i32)> = watched.iter()
+                                                .filter(|&(dir, _)| !dirs.contains(dir))
+                                                .map(|(dir, &wd)| (dir.clone(), wd))
+                                                .collect();
+        for (dir, wd) in stale {
+            watched.remove(&dir);
+            // Two paths for the same directory share a watch.
+            if !watched.values().any(|&other| other == wd) {
+                sys::rm_watch(self.fd, wd);
+            }
+        }
+        Ok(())
+    }
+
+    /// Wait up to `timeout` for something to change in the watched
+    /// directories, returning whether anything has changed since the last
+    /// call.
+    pub fn wait(&self, timeout: Duration) -> bool {
+        sys::wait(self.fd, timeout)
+    }
+}
+
+impl Drop for Watcher {
+    fn drop(&mut self) {
+        sys::close(self.fd);
+    }
+}